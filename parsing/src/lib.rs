@@ -29,7 +29,8 @@ lazy_static! {
     static ref LINE_SUB_REGEX: Regex =
         Regex::new(r"^(\d+)?s/(.*?(?<!\\))/(.*?(?<!\\))(?:/|$)(\w+)?").unwrap();
     static ref REACTION_REGEX: Regex =
-        Regex::new(r"^(\d+)?([\+\-])(.).*$").unwrap();
+        Regex::new(r"^(\d+)?([\+\-])(:[\w\+\-]+:|\X).*$").unwrap();
+    static ref REPLY_REGEX: Regex = Regex::new(r"^(\d+)>>\s?(.+)$").unwrap();
 }
 
 #[derive(Debug)]
@@ -45,12 +46,25 @@ pub enum LineEdit<'a> {
     },
 }
 
+#[derive(Debug)]
+pub enum ReactionEmoji<'a> {
+    /// A literal emoji, which may be made of multiple codepoints
+    Unicode(&'a str),
+    /// The name of a `:shortcode:`, without the colons
+    Shortcode(&'a str),
+}
 
 #[derive(Debug)]
 pub struct Reaction<'a> {
     pub add: bool,
-    pub unicode: &'a str,
+    pub emoji: ReactionEmoji<'a>,
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct Reply<'a> {
     pub line: usize,
+    pub text: &'a str,
 }
 
 pub fn parse_line_edit(input: &str) -> Option<LineEdit> {
@@ -75,7 +89,177 @@ pub fn parse_line_edit(input: &str) -> Option<LineEdit> {
 pub fn parse_reaction(input: &str) -> Option<Reaction> {
     let caps = REACTION_REGEX.captures(input)?;
     let line = caps.at(1).and_then(|l| l.parse().ok()).unwrap_or(1);
-    let unicode_opt = caps.at(3);
+    let emoji = caps.at(3)?;
     let add = caps.at(2) == Some("+");
-    unicode_opt.map(|unicode| Reaction{ add, unicode, line })
+    let emoji = if emoji.len() > 2 && emoji.starts_with(':') && emoji.ends_with(':') {
+        ReactionEmoji::Shortcode(&emoji[1..emoji.len() - 1])
+    } else {
+        ReactionEmoji::Unicode(emoji)
+    };
+    Some(Reaction { add, emoji, line })
+}
+
+/// Parse a reply to the nth most recent message, eg: `3>>some text`
+///
+/// Unlike edits and reactions, the line number is required, as a leading `>` is a block quote, and
+/// it is followed by two `>` so messages like `3>2` are not sent as replies
+pub fn parse_reply(input: &str) -> Option<Reply> {
+    let caps = REPLY_REGEX.captures(input)?;
+    let line = caps.at(1)?.parse::<usize>().ok().filter(|&l| l > 0)?;
+    let text = caps.at(2)?;
+    Some(Reply { line, text })
+}
+
+/// The longest duration `parse_duration` accepts, in seconds
+pub const MAX_DURATION: u64 = 365 * 24 * 60 * 60;
+
+/// Parse a duration like `30m`, `8h` or `1d` into seconds, plain numbers are minutes
+///
+/// Durations longer than `MAX_DURATION` are rejected
+pub fn parse_duration(duration: &str) -> Option<u64> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| duration.len());
+    let (count, unit) = duration.split_at(split);
+    let unit = match unit {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    count
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .filter(|&duration| duration <= MAX_DURATION)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unicode(input: &str) -> Option<&str> {
+        match parse_reaction(input)?.emoji {
+            ReactionEmoji::Unicode(emoji) => Some(emoji),
+            ReactionEmoji::Shortcode(_) => None,
+        }
+    }
+
+    fn shortcode(input: &str) -> Option<&str> {
+        match parse_reaction(input)?.emoji {
+            ReactionEmoji::Shortcode(name) => Some(name),
+            ReactionEmoji::Unicode(_) => None,
+        }
+    }
+
+    #[test]
+    fn reaction_parses_line_and_direction() {
+        let reaction = parse_reaction("+👍").unwrap();
+        assert!(reaction.add);
+        assert_eq!(reaction.line, 1);
+
+        let reaction = parse_reaction("3-👍").unwrap();
+        assert!(!reaction.add);
+        assert_eq!(reaction.line, 3);
+    }
+
+    #[test]
+    fn reaction_unicode_emoji_are_whole_grapheme_clusters() {
+        assert_eq!(unicode("+👍"), Some("👍"));
+        assert_eq!(unicode("+👍🏽"), Some("👍🏽"));
+        assert_eq!(
+            unicode("+👨\u{200d}👩\u{200d}👧"),
+            Some("👨\u{200d}👩\u{200d}👧")
+        );
+        assert_eq!(unicode("+❤\u{fe0f}"), Some("❤\u{fe0f}"));
+    }
+
+    #[test]
+    fn reaction_only_the_first_emoji_is_used() {
+        assert_eq!(unicode("+👍👎"), Some("👍"));
+        assert_eq!(unicode("+👍 nice"), Some("👍"));
+    }
+
+    #[test]
+    fn reaction_shortcodes_drop_their_colons() {
+        assert_eq!(shortcode("+:partyparrot:"), Some("partyparrot"));
+        assert_eq!(shortcode("2-:thumbsup:"), Some("thumbsup"));
+        assert_eq!(shortcode("+:+1:"), Some("+1"));
+        assert_eq!(shortcode("+:flag-us:"), Some("flag-us"));
+    }
+
+    #[test]
+    fn reaction_ignores_other_messages() {
+        assert!(parse_reaction("").is_none());
+        assert!(parse_reaction("+").is_none());
+        assert!(parse_reaction("hello +👍").is_none());
+    }
+
+    #[test]
+    fn reply_parses_line_and_text() {
+        let reply = parse_reply("2>>sounds good").unwrap();
+        assert_eq!(reply.line, 2);
+        assert_eq!(reply.text, "sounds good");
+    }
+
+    #[test]
+    fn reply_skips_one_space() {
+        assert_eq!(parse_reply("12>> hi").unwrap().text, "hi");
+        assert_eq!(parse_reply("1>>  hi").unwrap().text, " hi");
+    }
+
+    #[test]
+    fn reply_requires_a_line() {
+        assert!(parse_reply(">quoted").is_none());
+        assert!(parse_reply(">>quoted").is_none());
+        assert!(parse_reply("0>>text").is_none());
+    }
+
+    #[test]
+    fn reply_requires_text() {
+        assert!(parse_reply("2>>").is_none());
+    }
+
+    #[test]
+    fn reply_ignores_other_messages() {
+        assert!(parse_reply("a 2>>b").is_none());
+        assert!(parse_reply("2 >> b").is_none());
+        assert!(parse_reply("3>2 is true").is_none());
+        assert!(parse_reply("2> quoted").is_none());
+    }
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("45s"), Some(45));
+        assert_eq!(parse_duration("90m"), Some(90 * 60));
+        assert_eq!(parse_duration("8h"), Some(8 * 60 * 60));
+        assert_eq!(parse_duration("1d"), Some(24 * 60 * 60));
+        assert_eq!(parse_duration("2w"), Some(2 * 7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn plain_numbers_are_minutes() {
+        assert_eq!(parse_duration("30"), Some(30 * 60));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("1y"), None);
+        assert_eq!(parse_duration("1h30m"), None);
+        assert_eq!(parse_duration("-1h"), None);
+        assert_eq!(parse_duration("guild"), None);
+    }
+
+    #[test]
+    fn rejects_durations_over_a_year() {
+        assert_eq!(parse_duration("365d"), Some(MAX_DURATION));
+        assert_eq!(parse_duration("366d"), None);
+        assert_eq!(parse_duration("53w"), None);
+        assert_eq!(parse_duration("40000000000000w"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), None);
+    }
 }
@@ -8,6 +8,8 @@ pub struct BarHandles {
     _channel_name: BarItem<()>,
     _full_name: BarItem<()>,
     _typing_indicator: BarItem<()>,
+    _unread: BarItem<()>,
+    _connection: BarItem<()>,
 }
 
 pub fn init(weechat: &Weechat) -> BarHandles {
@@ -59,11 +61,12 @@ pub fn init(weechat: &Weechat) -> BarHandles {
                 let max_users = config.user_typing_list_max.value() as usize;
                 let expanded = config.user_typing_list_expanded.value();
                 let guild_id = buffer.guild_id();
+                let account = buffer.account();
 
                 if expanded {
-                    expanded_typing_list(channel_id, guild_id, max_users)
+                    expanded_typing_list(&account, channel_id, guild_id, max_users)
                 } else {
-                    terse_typing_list(channel_id, guild_id, max_users)
+                    terse_typing_list(&account, channel_id, guild_id, max_users)
                 }
             } else {
                 "".into()
@@ -72,16 +75,50 @@ pub fn init(weechat: &Weechat) -> BarHandles {
         None,
     );
 
+    let _unread = weechat.new_bar_item(
+        "discord_unread",
+        |_, _, _| {
+            let unread = crate::discord::read_state::total_unread();
+            match (unread.messages, unread.mentions) {
+                (0, 0) => String::new(),
+                (messages, 0) => format!("unread: {}", messages),
+                (messages, mentions) => format!("unread: {} ({} mentions)", messages, mentions),
+            }
+        },
+        None,
+    );
+
+    let _connection = weechat.new_bar_item(
+        "discord_connection",
+        |_, _, buffer| {
+            let account = buffer.account();
+            let state = crate::discord::connection::state(&account);
+            if account == crate::config::MAIN_ACCOUNT {
+                state.to_string()
+            } else {
+                format!("{}: {}", account, state)
+            }
+        },
+        None,
+    );
+
     BarHandles {
         _guild_name,
         _channel_name,
         _full_name,
         _typing_indicator,
+        _unread,
+        _connection,
     }
 }
 
-fn terse_typing_list(channel_id: ChannelId, guild_id: Option<GuildId>, max_names: usize) -> String {
-    let (head, has_more) = get_users_for_typing_list(channel_id, guild_id, max_names);
+fn terse_typing_list(
+    account: &str,
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+    max_names: usize,
+) -> String {
+    let (head, has_more) = get_users_for_typing_list(account, channel_id, guild_id, max_names);
 
     let mut users = head.join(", ");
     if has_more {
@@ -95,11 +132,12 @@ fn terse_typing_list(channel_id: ChannelId, guild_id: Option<GuildId>, max_names
 }
 
 fn expanded_typing_list(
+    account: &str,
     channel_id: ChannelId,
     guild_id: Option<GuildId>,
     max_names: usize,
 ) -> String {
-    let (head, has_more) = get_users_for_typing_list(channel_id, guild_id, max_names);
+    let (head, has_more) = get_users_for_typing_list(account, channel_id, guild_id, max_names);
 
     if head.is_empty() {
         "".into()
@@ -118,6 +156,7 @@ fn expanded_typing_list(
 }
 
 fn get_users_for_typing_list(
+    account: &str,
     channel_id: ChannelId,
     guild_id: Option<GuildId>,
     max_names: usize,
@@ -126,7 +165,7 @@ fn get_users_for_typing_list(
         .lock()
         .entries
         .iter()
-        .filter(|e| e.guild_id == guild_id && e.channel_id == channel_id)
+        .filter(|e| e.account == account && e.guild_id == guild_id && e.channel_id == channel_id)
         .map(|e| e.user_name.clone())
         .collect::<Vec<_>>();
     users.dedup();
@@ -1,12 +1,21 @@
 use crate::{
-    on_main,
+    discord::{
+        guild_folders,
+        guild_settings::{self, NotifyLevel},
+        read_state,
+        stickers::{self, Stickers},
+        threads::{self, Thread},
+    },
+    message_cache, on_main,
     sync::on_main_blocking,
-    utils,
+    tasks, utils,
     utils::{BufferExt, ChannelExt},
-    weechat_utils::{BufferManager, MessageManager},
+    weechat_utils::{raw, BufferManager, MessageManager},
     Discord,
 };
+use chrono::Utc;
 use indexmap::IndexMap;
+use lazy_static::lazy_static;
 use serenity::{
     cache::{Cache, CacheRwLock},
     client::bridge::gateway,
@@ -17,6 +26,7 @@ use serenity::{
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     sync::Arc,
+    time::Duration,
 };
 use weechat::{buffer::HotlistPriority, Buffer, ConfigOption, NickArgs, Weechat};
 
@@ -24,15 +34,23 @@ const OFFLINE_GROUP_NAME: &str = "99999|Offline";
 const ONLINE_GROUP_NAME: &str = "99998|Online";
 const BOT_GROUP_NAME: &str = "99997|Bot";
 
+const MUTED_SUFFIX: &str = " (muted)";
+
+lazy_static! {
+    /// When the temporary mute of each buffer ends, a timer to refresh it is running for each
+    static ref MUTE_TIMERS: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
+}
+
 pub fn init(weechat: &Weechat) -> BufferManager {
     BufferManager::new(Weechat::from_ptr(weechat.as_ptr()))
 }
 
-pub fn create_buffers(ready_data: &Ready) {
-    let ctx = match crate::discord::get_ctx() {
+pub fn create_buffers(account: &str, ready_data: &Ready) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
     let current_user = ctx.cache.read().user.clone();
 
     let guilds = match current_user.guilds(ctx) {
@@ -46,8 +64,13 @@ pub fn create_buffers(ready_data: &Ready) {
 
     let mut sorted_guilds = VecDeque::new();
 
-    // Add the guilds ordered from the client
-    for guild_id in &ready_data.user_settings.guild_positions {
+    // Add the guilds ordered from the client, serenity only knows the order without folders, the
+    // buffers are sorted again once the folders are fetched
+    let mut guild_positions = guild_folders::guild_order(account);
+    if guild_positions.is_empty() {
+        guild_positions = ready_data.user_settings.guild_positions.clone();
+    }
+    for guild_id in &guild_positions {
         if let Some(guild) = map.remove(&guild_id) {
             sorted_guilds.push_back(guild);
         }
@@ -59,49 +82,81 @@ pub fn create_buffers(ready_data: &Ready) {
     }
 
     for guild in &sorted_guilds {
-        let guild_settings = ready_data.user_guild_settings.get(&guild.id.into());
-        let guild_muted;
-        let mut channel_muted = HashMap::new();
-        if let Some(guild_settings) = guild_settings {
-            guild_muted = guild_settings.muted;
-            for (channel_id, channel_override) in &guild_settings.channel_overrides {
-                channel_muted.insert(channel_id, channel_override.muted);
-            }
-        } else {
-            guild_muted = false;
-        }
-        create_guild_buffer(guild.id, &guild.name);
+        create_guild_buffers(ctx, account, guild.id, &guild.name);
+    }
+    sort_buffers(account);
+}
 
-        // TODO: Colors?
-        let nick = if let Ok(current_member) = guild.id.member(ctx, current_user.id) {
-            format!("@{}", current_member.display_name())
+/// Create the buffer of a guild and the buffers of all of its channels
+pub fn create_guild_buffers(ctx: &Context, account: &str, guild_id: GuildId, guild_name: &str) {
+    create_guild_buffer(account, guild_id, guild_name);
+
+    let nick = guild_nick(ctx, guild_id);
+    let channels = match guild_id.channels(ctx) {
+        Ok(channels) => channels,
+        Err(e) => {
+            crate::plugin_print(&format!(
+                "Unable to fetch channels of {}: {}",
+                guild_name, e
+            ));
+            return;
+        },
+    };
+    let mut channels = channels.values().collect::<Vec<_>>();
+    sort_channels(&mut channels);
+    for channel in channels {
+        create_buffer_from_channel(&ctx.cache, account, guild_name, &channel, &nick);
+    }
+}
+
+/// The nick of the current user in a guild
+pub fn guild_nick(ctx: &Context, guild_id: GuildId) -> String {
+    let current_user = ctx.cache.read().user.clone();
+    // TODO: Colors?
+    if let Ok(current_member) = guild_id.member(ctx, current_user.id) {
+        format!("@{}", current_member.display_name())
+    } else {
+        format!("@{}", current_user.name)
+    }
+}
+
+/// Sort channels like the client does, channels without a category first and then every category
+/// followed by its channels
+fn sort_channels(channels: &mut Vec<&GuildChannel>) {
+    let categories: HashMap<_, _> = channels
+        .iter()
+        .filter(|channel| channel.kind == ChannelType::Category)
+        .map(|category| (category.id, category.position))
+        .collect();
+
+    channels.sort_by_key(|channel| {
+        let is_category = channel.kind == ChannelType::Category;
+        let category = if is_category {
+            Some(channel.id)
         } else {
-            format!("@{}", current_user.name)
+            channel.category_id
         };
-        let channels = guild.id.channels(ctx).expect("Unable to fetch channels");
-        let mut channels = channels.values().collect::<Vec<_>>();
-        channels.sort_by_key(|g| g.position);
-        for channel in channels {
-            let is_muted =
-                guild_muted || channel_muted.get(&channel.id).cloned().unwrap_or_default();
-            create_buffer_from_channel(&ctx.cache, &guild.name, &channel, &nick, is_muted);
-        }
-    }
+        let category = category.map(|id| (categories.get(&id).cloned().unwrap_or_default(), id));
+        (category, !is_category, channel.position, channel.id)
+    });
 }
 
 // TODO: Merge these functions
-pub fn create_autojoin_buffers(_ready: &Ready) {
-    let ctx = match crate::discord::get_ctx() {
+pub fn create_autojoin_buffers(account: &str, _ready: &Ready) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
 
     let current_user = ctx.cache.read().user.clone();
 
     // TODO: Add sorting
-    let mut autojoin_items: Vec<_> = on_main_blocking(|weecord| weecord.config.autojoin_channels());
+    let mut autojoin_items: Vec<_> =
+        on_main_blocking(|weecord| weecord.config.autojoin_channels(account));
 
-    let watched_items: Vec<_> = on_main_blocking(|weecord| weecord.config.watched_channels());
+    let watched_items: Vec<_> =
+        on_main_blocking(|weecord| weecord.config.watched_channels(account));
 
     let watched_channels = utils::flatten_guilds(&ctx, &watched_items);
 
@@ -129,11 +184,13 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
     // flatten guilds into channels
     let autojoin_channels = utils::flatten_guilds(&ctx, &autojoin_items);
 
-    create_buffers_from_flat_items(&ctx, &current_user, &autojoin_channels);
+    create_buffers_from_flat_items(&ctx, account, &current_user, &autojoin_channels);
+    sort_buffers(account);
 }
 
 pub fn create_buffers_from_flat_items(
     ctx: &Context,
+    account: &str,
     current_user: &CurrentUser,
     channels: &IndexMap<Option<GuildId>, Vec<ChannelId>>,
 ) {
@@ -155,17 +212,17 @@ pub fn create_buffers_from_flat_items(
                 };
                 let nick = &nick;
 
-                create_guild_buffer(guild.id, &guild.name);
+                create_guild_buffer(account, guild.id, &guild.name);
 
                 parking_lot::RwLockReadGuard::unlock_fair(guild);
 
                 for channel in channels {
-                    // TODO: Muting
                     let () = on_main_blocking(move |_| {
-                        let ctx = match crate::discord::get_ctx() {
+                        let ctx = match crate::discord::get_ctx(account) {
                             Some(ctx) => ctx,
                             _ => return,
                         };
+                        let ctx = &*ctx;
 
                         let guild = match guild_id.to_guild_cached(&ctx.cache) {
                             Some(guild) => guild,
@@ -183,19 +240,20 @@ pub fn create_buffers_from_flat_items(
 
                         create_buffer_from_channel(
                             &ctx.cache,
+                            account,
                             &guild.name,
                             &channel.read(),
                             &nick,
-                            false,
                         );
                     });
                 }
             },
             (None, channels) => {
-                let ctx = match crate::discord::get_ctx() {
+                let ctx = match crate::discord::get_ctx(account) {
                     Some(ctx) => ctx,
                     _ => return,
                 };
+                let ctx = &*ctx;
                 let cache = ctx.cache.read();
                 let nick = cache.user.name.to_string();
 
@@ -208,21 +266,26 @@ pub fn create_buffers_from_flat_items(
                         continue;
                     };
 
+                    let account = account.to_owned();
                     match channel {
                         channel @ Channel::Private(_) => on_main(move |weecord| {
-                            let ctx = match crate::discord::get_ctx() {
+                            let ctx = match crate::discord::get_ctx(&account) {
                                 Some(ctx) => ctx,
                                 _ => return,
                             };
-                            create_buffer_from_dm(&ctx.cache, weecord, channel, &nick, false);
+                            let ctx = &*ctx;
+                            create_buffer_from_dm(
+                                &ctx.cache, weecord, &account, channel, &nick, false,
+                            );
                         }),
 
                         channel @ Channel::Group(_) => on_main(move |weecord| {
-                            let ctx = match crate::discord::get_ctx() {
+                            let ctx = match crate::discord::get_ctx(&account) {
                                 Some(ctx) => ctx,
                                 _ => return,
                             };
-                            create_buffer_from_group(&ctx.cache, weecord, channel, &nick);
+                            let ctx = &*ctx;
+                            create_buffer_from_group(&ctx.cache, weecord, &account, channel, &nick);
                         }),
                         _ => unreachable!(),
                     }
@@ -243,25 +306,34 @@ fn user_online(cache: &Cache, user_id: UserId) -> bool {
     }
 }
 
-pub fn create_guild_buffer(id: GuildId, name: &str) {
-    let guild_name_id = utils::buffer_id_for_guild(id);
+pub fn create_guild_buffer(account: &str, id: GuildId, name: &str) {
+    let guild_name_id = utils::buffer_id_for_guild(account, id);
     let () = on_main_blocking(move |weecord| {
         let buffer = weecord.buffer_manager.get_or_create_buffer(&guild_name_id);
 
+        buffer.set_localvar("account", account);
         buffer.set_localvar("guild_name", name);
         buffer.set_localvar("server", name);
         buffer.set_short_name(name);
         buffer.set_localvar("guildid", &id.0.to_string());
         buffer.set_localvar("type", "server");
+        if let Some(folder) = guild_folders::folder_of(account, id) {
+            buffer.set_localvar("folder", &folder);
+        }
+        buffer.set_title(name);
+
+        if let Some(ctx) = crate::discord::get_ctx(account) {
+            apply_notification_settings(&ctx.cache, &weecord.weechat, &buffer);
+        }
     });
 }
 
 pub fn create_buffer_from_channel(
     cache: &CacheRwLock,
+    account: &str,
     guild_name: &str,
     channel: &GuildChannel,
     nick: &str,
-    muted: bool,
 ) {
     let current_user = cache.read().user.clone();
     if let Ok(perms) = channel.permissions_for_user(cache, current_user.id) {
@@ -278,7 +350,13 @@ pub fn create_buffer_from_channel(
         ChannelType::__Nonexhaustive => unreachable!(),
     };
 
-    let name_id = utils::buffer_id_for_channel(Some(channel.guild_id), channel.id);
+    let name_id = utils::buffer_id_for_channel(account, Some(channel.guild_id), channel.id);
+    let category = channel
+        .category_id
+        .and_then(|category| category.to_channel_cached(cache))
+        .and_then(Channel::category)
+        .map(|category| category.read().clone());
+    let folder = guild_folders::folder_of(account, channel.guild_id);
     let has_unread = cache
         .read()
         .read_state
@@ -291,6 +369,7 @@ pub fn create_buffer_from_channel(
 
         buffer.set_short_name(&channel.name);
 
+        buffer.set_localvar("account", account);
         buffer.set_localvar("channelid", &channel.id.0.to_string());
         buffer.set_localvar("guildid", &channel.guild_id.0.to_string());
         buffer.set_localvar("channel", &channel.name);
@@ -298,32 +377,322 @@ pub fn create_buffer_from_channel(
         buffer.set_localvar("server", guild_name);
         buffer.set_localvar("type", channel_type);
         buffer.set_localvar("nick", &nick);
+        if let Some(category) = &category {
+            buffer.set_localvar("category", &category.name);
+            create_category_buffer(weecord, account, channel.guild_id, guild_name, category);
+        }
+        if let Some(folder) = &folder {
+            buffer.set_localvar("folder", folder);
+        }
+        buffer.set_localvar("topic", channel.topic.as_deref().unwrap_or_default());
+        buffer.set_title(&channel_title(channel));
+
+        let muted = apply_notification_settings(cache, &weecord.weechat, &buffer);
+        apply_collapsed(cache, weecord, &buffer);
+        read_state::update_buffer(&buffer, channel.id);
         if has_unread && !muted {
             buffer.set_hotlist(HotlistPriority::Message);
         }
 
-        let mut title = if let Some(ref topic) = channel.topic {
-            if !topic.is_empty() {
-                format!("{} | {}", channel.name, topic)
-            } else {
-                channel.name.clone()
+        // Threads that were fetched before the buffer of their channel existed
+        for thread in threads::threads_in(account, channel.id) {
+            let has_buffer = weecord
+                .buffer_manager
+                .get_buffer(&utils::buffer_id_for_channel(
+                    account,
+                    Some(thread.guild_id),
+                    thread.id,
+                ))
+                .is_some();
+            if thread.joined && !thread.archived && !has_buffer {
+                create_buffer_from_thread(cache, weecord, account, &thread, false);
             }
-        } else {
-            channel.name.clone()
+        }
+    });
+}
+
+/// Create the buffer heading the channels of a category in the buffer list, it has no channel of
+/// its own so input and history are ignored
+fn create_category_buffer(
+    weecord: &Discord,
+    account: &str,
+    guild_id: GuildId,
+    guild_name: &str,
+    category: &ChannelCategory,
+) {
+    let name_id = utils::buffer_id_for_channel(account, Some(guild_id), category.id);
+    let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
+
+    buffer.set_short_name(&category.name);
+    buffer.set_localvar("account", account);
+    buffer.set_localvar("guildid", &guild_id.0.to_string());
+    buffer.set_localvar("guild_name", guild_name);
+    buffer.set_localvar("server", guild_name);
+    buffer.set_localvar("type", "category");
+    buffer.set_localvar("category", &category.name);
+    if let Some(folder) = guild_folders::folder_of(account, guild_id) {
+        buffer.set_localvar("folder", &folder);
+    }
+    buffer.set_title(&category.name);
+    raw::buffer_set(&weecord.weechat, &name_id, "notify", "0");
+}
+
+/// Rename the buffer of a category, if it has one
+pub fn update_category_buffer(account: &str, guild_id: GuildId, category: &ChannelCategory) {
+    let name_id = utils::buffer_id_for_channel(account, Some(guild_id), category.id);
+    let () = on_main_blocking(|weecord| {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&name_id) {
+            buffer.set_short_name(&category.name);
+            buffer.set_localvar("category", &category.name);
+            buffer.set_title(&category.name);
+        }
+    });
+}
+
+/// Close the buffer of a deleted category, its channels are moved out of it by Discord
+pub fn close_category_buffer(account: &str, guild_id: GuildId, category_id: ChannelId) {
+    let name_id = utils::buffer_id_for_channel(account, Some(guild_id), category_id);
+    on_main(move |weecord| weecord.buffer_manager.close_buffer(&name_id));
+}
+
+/// Number the buffers of an account like the client orders them: guilds in the order of their
+/// folders, each followed by its categories and channels, and every channel by its threads
+pub fn sort_buffers(account: &str) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+        let weechat = &weecord.weechat;
+        let buffers = &weecord.buffer_manager;
+        let number = |name: &str| raw::buffer_get_integer(weechat, name, "number");
+
+        let folder_order = guild_folders::guild_order(&account);
+        let mut guilds: Vec<_> = buffers
+            .buffer_names()
+            .into_iter()
+            .filter_map(|name| {
+                let buffer = buffers.get_buffer(&name)?;
+                if buffer.get_localvar("type").as_deref() != Some("server")
+                    || buffer.account() != account
+                {
+                    return None;
+                }
+                Some((buffer.guild_id()?, number(&name)?))
+            })
+            .collect();
+        // Guilds outside of the folders were added first, the rest keep their order otherwise
+        guilds.sort_by_key(|&(guild_id, number)| {
+            let folder_position = folder_order.iter().position(|&id| id == guild_id);
+            (folder_position.map_or(0, |position| position + 1), number)
+        });
+
+        let mut next = match guilds.iter().map(|&(_, number)| number).min() {
+            Some(number) => number,
+            None => return,
+        };
+        for (guild_id, _) in guilds {
+            let mut names = vec![utils::buffer_id_for_guild(&account, guild_id)];
+            let channels: Vec<_> = match guild_id.to_guild_cached(&ctx.cache) {
+                Some(guild) => guild
+                    .read()
+                    .channels
+                    .values()
+                    .map(|channel| channel.read().clone())
+                    .collect(),
+                None => continue,
+            };
+            let mut channels: Vec<_> = channels.iter().collect();
+            sort_channels(&mut channels);
+            for channel in channels {
+                let name = utils::buffer_id_for_channel(&account, Some(guild_id), channel.id);
+                let buffer = match buffers.get_buffer(&name) {
+                    Some(buffer) => buffer,
+                    None => continue,
+                };
+                let threads = buffer.get_localvar("threads").unwrap_or_default();
+                names.push(name);
+                for thread in threads.split(',').filter_map(|id| id.parse().ok()) {
+                    names.push(utils::buffer_id_for_channel(
+                        &account,
+                        Some(guild_id),
+                        ChannelId(thread),
+                    ));
+                }
+            }
+
+            let folder = guild_folders::folder_of(&account, guild_id);
+            for name in names {
+                let buffer = match buffers.get_buffer(&name) {
+                    Some(buffer) => buffer,
+                    None => continue,
+                };
+                if let Some(folder) = &folder {
+                    buffer.set_localvar("folder", folder);
+                }
+                raw::buffer_set(weechat, &name, "number", &next.to_string());
+                next += 1;
+            }
+        }
+    });
+}
+
+fn channel_title(channel: &GuildChannel) -> String {
+    match channel.topic {
+        Some(ref topic) if !topic.is_empty() => format!("{} | {}", channel.name, topic),
+        _ => channel.name.clone(),
+    }
+}
+
+/// Update the buffer of a channel that was renamed, moved or had its topic changed, if it is open
+pub fn update_channel_buffer(cache: &CacheRwLock, account: &str, channel: &GuildChannel) {
+    let name_id = utils::buffer_id_for_channel(account, Some(channel.guild_id), channel.id);
+    let category = channel
+        .category_id
+        .and_then(|category| category.to_channel_cached(cache))
+        .map(|category| category.name())
+        .unwrap_or_default();
+
+    let () = on_main_blocking(|weecord| {
+        let buffer = match weecord.buffer_manager.get_buffer(&name_id) {
+            Some(buffer) => buffer,
+            None => return,
         };
 
-        if muted {
-            title += " (muted)";
+        buffer.set_short_name(&channel.name);
+        buffer.set_localvar("channel", &channel.name);
+        buffer.set_localvar("category", &category);
+        buffer.set_localvar("topic", channel.topic.as_deref().unwrap_or_default());
+        buffer.set_title(&channel_title(channel));
+
+        apply_notification_settings(cache, &weecord.weechat, &buffer);
+        apply_collapsed(cache, weecord, &buffer);
+    });
+}
+
+/// Mark the buffer of a deleted channel, it is kept open so its messages can still be read
+pub fn mark_channel_deleted(account: &str, guild_id: Option<GuildId>, channel_id: ChannelId) {
+    let name_id = utils::buffer_id_for_channel(account, guild_id, channel_id);
+    on_main(move |weecord| {
+        let buffer = match weecord.buffer_manager.get_buffer(&name_id) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+
+        let title = raw::buffer_get_string(&weecord.weechat, &name_id, "title").unwrap_or_default();
+        buffer.set_title(&format!("{} (deleted)", title));
+        buffer.set_localvar("deleted", "1");
+        raw::buffer_set(&weecord.weechat, &name_id, "notify", "0");
+        buffer.print(&format!(
+            "{}\tThis channel has been deleted",
+            weecord.get_prefix("network")
+        ));
+    });
+}
+
+/// Close the buffers of a guild and all of its channels
+pub fn close_guild_buffers(account: &str, guild_id: GuildId) {
+    let guild_name_id = utils::buffer_id_for_guild(account, guild_id);
+    on_main(move |weecord| {
+        let channel_prefix = format!("{}.", guild_name_id);
+        for name in weecord.buffer_manager.buffer_names() {
+            if name == guild_name_id || name.starts_with(&channel_prefix) {
+                weecord.buffer_manager.close_buffer(&name);
+            }
         }
-        buffer.set_title(&title);
-        buffer.set_localvar("muted", &(muted as u8).to_string());
     });
 }
 
+/// Create (or refresh) the buffer of a thread, placed directly after its parent channel
+pub fn create_buffer_from_thread(
+    cache: &CacheRwLock,
+    weecord: &Discord,
+    account: &str,
+    thread: &Thread,
+    switch_to: bool,
+) {
+    let weechat = &weecord.weechat;
+    let name_id = utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.id);
+    let parent_name_id =
+        utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.parent_id);
+
+    let is_new = weecord.buffer_manager.get_buffer(&name_id).is_none();
+    let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
+    let parent = weecord.buffer_manager.get_buffer(&parent_name_id);
+
+    let guild_name = thread
+        .guild_id
+        .to_guild_cached(cache)
+        .map(|guild| guild.read().name.clone())
+        .unwrap_or_default();
+    let parent_name = thread
+        .parent_id
+        .to_channel_cached(cache)
+        .map(|channel| channel.name())
+        .unwrap_or_default();
+
+    buffer.set_short_name(&format!("↳{}", thread.name));
+    buffer.set_localvar("account", account);
+    buffer.set_localvar("channelid", &thread.id.0.to_string());
+    buffer.set_localvar("guildid", &thread.guild_id.0.to_string());
+    buffer.set_localvar("parent_channelid", &thread.parent_id.0.to_string());
+    buffer.set_localvar("parent_buffer", &parent_name_id);
+    buffer.set_localvar("channel", &thread.name);
+    buffer.set_localvar("guild_name", &guild_name);
+    buffer.set_localvar("server", &guild_name);
+    buffer.set_localvar("type", "channel");
+
+    let mut title = format!("{} ▸ {}", parent_name, thread.name);
+    if thread.archived {
+        title += " (archived)";
+    }
+
+    if let Some(parent) = parent {
+        if let Some(nick) = parent.get_localvar("nick") {
+            buffer.set_localvar("nick", &nick);
+        }
+        let threads = parent.get_localvar("threads").unwrap_or_default();
+        let mut threads: Vec<_> = threads.split(',').filter(|t| !t.is_empty()).collect();
+        let id = thread.id.0.to_string();
+        if !threads.contains(&id.as_str()) {
+            threads.push(&id);
+            parent.set_localvar("threads", &threads.join(","));
+        }
+
+        if is_new {
+            if let Some(number) = raw::buffer_get_integer(weechat, &parent_name_id, "number") {
+                raw::buffer_set(weechat, &name_id, "number", &(number + 1).to_string());
+            }
+        }
+    } else {
+        buffer.set_localvar("nick", &format!("@{}", cache.read().user.name));
+    }
+    buffer.set_title(&title);
+    let muted = apply_notification_settings(cache, weechat, &buffer);
+    read_state::update_buffer(&buffer, thread.id);
+
+    let has_unread = cache
+        .read()
+        .read_state
+        .get(&thread.id)
+        .map(|rs| rs.last_message_id)
+        != thread.last_message_id;
+    if is_new && has_unread && !muted {
+        buffer.set_hotlist(HotlistPriority::Message);
+    }
+
+    if switch_to {
+        buffer.switch_to();
+    }
+}
+
 // TODO: Reduce code duplication
 pub fn create_buffer_from_dm(
     cache: &CacheRwLock,
     weecord: &crate::Discord,
+    account: &str,
     channel: Channel,
     nick: &str,
     switch_to: bool,
@@ -334,12 +703,17 @@ pub fn create_buffer_from_dm(
     };
     let channel = channel.read();
 
-    let name_id = utils::buffer_id_for_channel(None, channel.id);
+    let name_id = utils::buffer_id_for_channel(account, None, channel.id);
     let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
     buffer.set_short_name(&channel.name());
+    buffer.set_localvar("account", account);
     buffer.set_localvar("channelid", &channel.id.0.to_string());
     buffer.set_localvar("nick", &nick);
+    let title = format!("DM with {}", channel.recipient.read().name);
+    buffer.set_title(&title);
+    let muted = apply_notification_settings(cache, &weecord.weechat, &buffer);
+    read_state::update_buffer(&buffer, channel.id);
 
     let has_unread = cache
         .read()
@@ -348,15 +722,13 @@ pub fn create_buffer_from_dm(
         .map(|rs| rs.last_message_id)
         != channel.last_message_id;
 
-    if has_unread {
+    if has_unread && !muted {
         buffer.set_hotlist(HotlistPriority::Private);
     }
 
     if switch_to {
         buffer.switch_to();
     }
-    let title = format!("DM with {}", channel.recipient.read().name);
-    buffer.set_title(&title);
 
     load_dm_nicks(&buffer, &*channel);
 }
@@ -364,6 +736,7 @@ pub fn create_buffer_from_dm(
 pub fn create_buffer_from_group(
     cache: &CacheRwLock,
     weecord: &Discord,
+    account: &str,
     channel: Channel,
     nick: &str,
 ) {
@@ -383,14 +756,17 @@ pub fn create_buffer_from_group(
             .join(", ")
     );
 
-    let name_id = utils::buffer_id_for_channel(None, channel.channel_id);
+    let name_id = utils::buffer_id_for_channel(account, None, channel.channel_id);
 
     let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
     buffer.set_short_name(&channel.name());
+    buffer.set_localvar("account", account);
     buffer.set_localvar("channelid", &channel.channel_id.0.to_string());
     buffer.set_localvar("nick", &nick);
     buffer.set_title(&title);
+    let muted = apply_notification_settings(cache, &weecord.weechat, &buffer);
+    read_state::update_buffer(&buffer, channel.channel_id);
 
     let has_unread = cache
         .read()
@@ -399,17 +775,108 @@ pub fn create_buffer_from_group(
         .map(|rs| rs.last_message_id)
         != channel.last_message_id;
 
-    if has_unread {
+    if has_unread && !muted {
         buffer.set_hotlist(HotlistPriority::Private);
     }
 }
 
-pub fn create_pins_buffer(weecord: &Discord, channel: &Channel) {
-    let buffer_name = format!("Pins.{}", channel.id().0);
+/// Apply the Discord notification level and mute of a channel (or guild) to its buffer
+///
+/// The notify level of the buffer decides what reaches the hotlist, so mentions still notify in
+/// muted channels like they do in the official client. Returns whether the buffer is muted
+pub fn apply_notification_settings(
+    cache: &CacheRwLock,
+    weechat: &Weechat,
+    buffer: &Buffer,
+) -> bool {
+    let (guild_id, channel_id) = (buffer.guild_id(), buffer.channel_id());
+    if guild_id.is_none() && channel_id.is_none() {
+        return false;
+    }
+    let account = buffer.account();
+    let notifications = guild_settings::notifications(cache, &account, guild_id, channel_id);
+
+    let notify = match (notifications.level, notifications.muted) {
+        (NotifyLevel::Nothing, _) => "0",
+        (NotifyLevel::Mentions, _) | (NotifyLevel::All, true) => "1",
+        (NotifyLevel::All, false) => "3",
+    };
+    let name = buffer.get_name().to_string();
+    raw::buffer_set(weechat, &name, "notify", notify);
+    buffer.set_localvar("notify_level", notifications.level.as_str());
+    buffer.set_localvar("muted", &(notifications.muted as u8).to_string());
+
+    let title = raw::buffer_get_string(weechat, &name, "title").unwrap_or_default();
+    let title = title.trim_end_matches(MUTED_SUFFIX);
+    if notifications.muted {
+        buffer.set_title(&format!("{}{}", title, MUTED_SUFFIX));
+    } else {
+        buffer.set_title(title);
+    }
+
+    // Refresh once a temporary mute is over, timers can't be cancelled so one is only started
+    // when the end changes and timers for an end that no longer applies do nothing
+    let mut timers = MUTE_TIMERS.lock();
+    let end = match notifications.mute_end {
+        Some(end) => end,
+        None => {
+            timers.remove(&name);
+            return notifications.muted;
+        },
+    };
+    if timers.insert(name.clone(), end) == Some(end) {
+        return notifications.muted;
+    }
+    let delay = (end - Utc::now().timestamp()).max(0) as u64 + 1;
+    tasks::spawn_after(Duration::from_secs(delay), move || {
+        on_main(move |weecord| {
+            {
+                let mut timers = MUTE_TIMERS.lock();
+                if timers.get(&name) != Some(&end) {
+                    return;
+                }
+                timers.remove(&name);
+            }
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let ctx = &*ctx;
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&name) {
+                apply_notification_settings(&ctx.cache, &weecord.weechat, &buffer);
+            }
+        });
+    });
+
+    notifications.muted
+}
+
+/// Hide the buffer of a channel in a category that is collapsed in Discord, if enabled
+pub fn apply_collapsed(cache: &CacheRwLock, weecord: &Discord, buffer: &Buffer) {
+    let (guild_id, channel_id) = match (buffer.guild_id(), buffer.channel_id()) {
+        (Some(guild_id), Some(channel_id)) => (guild_id, channel_id),
+        _ => return,
+    };
+    let category = channel_id
+        .to_channel_cached(cache)
+        .and_then(Channel::guild)
+        .and_then(|channel| channel.read().category_id);
+
+    let collapsed = weecord.config.collapse_categories.value()
+        && category.map_or(false, |category| {
+            guild_settings::category_collapsed(&buffer.account(), guild_id, category)
+        });
+    let hidden = if collapsed { "1" } else { "0" };
+    raw::buffer_set(&weecord.weechat, &buffer.get_name(), "hidden", hidden);
+}
+
+pub fn create_pins_buffer(weecord: &Discord, account: &str, channel: &Channel) {
+    let buffer_name = utils::buffer_id_for_pins(account, channel.id());
 
     let buffer = weecord.buffer_manager.get_or_create_buffer(&buffer_name);
     buffer.switch_to();
 
+    buffer.set_localvar("account", account);
     buffer.set_title(&format!("Pinned messages in #{}", channel.name()));
     buffer.set_full_name(&format!("Pinned messages in ${}", channel.name()));
     buffer.set_short_name(&format!("#{} pins", channel.name()));
@@ -425,28 +892,32 @@ pub fn load_pin_buffer_history(buffer: &MessageManager) {
     buffer.set_history_loaded();
     buffer.clear();
     let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
 
-    std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+    tasks::spawn_for_channel(&buffer.account(), channel, move || {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
 
-        let pins = match channel.pins(ctx) {
+        let (pins, stickers) = match stickers::fetch_pins(ctx, channel) {
             Ok(pins) => pins,
             Err(_) => return,
         };
 
         on_main(move |weecord| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
+            let ctx = &*ctx;
             let buf = match weecord.buffer_manager.get_buffer(&buffer_name) {
                 Some(buf) => buf,
                 None => return,
             };
 
+            buf.add_stickers(stickers);
             for pin in pins.iter().rev() {
                 buf.add_message(&ctx.cache, pin, false);
             }
@@ -454,18 +925,43 @@ pub fn load_pin_buffer_history(buffer: &MessageManager) {
     });
 }
 
-pub fn load_pin_buffer_history_for_id(id: ChannelId) {
+pub fn load_pin_buffer_history_for_id(account: &str, id: ChannelId) {
+    let buffer_name = utils::buffer_id_for_pins(account, id);
     on_main(move |weecord| {
-        if let Some(buffer) = weecord.buffer_manager.get_buffer(&format!("Pins.{}", id)) {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
             load_pin_buffer_history(&buffer)
         };
     })
 }
 
-pub fn load_history(
+/// Print a page of history (oldest first), the read marker is drawn after the last read message
+fn print_history(
     buffer: &MessageManager,
-    completion_sender: crossbeam_channel::Sender<()>,
+    cache: &CacheRwLock,
+    msgs: &[Message],
+    last_read: Option<MessageId>,
+) -> HashSet<UserId> {
+    if let Some(last_read) = last_read {
+        buffer.set_last_read(last_read);
+    }
+
+    let mut unknown_users = HashSet::new();
+    for msg in msgs {
+        unknown_users.extend(buffer.add_message(cache, msg, false));
+    }
+    buffer.mark_read_if_caught_up();
+    // Messages being sent belong after the history
+    buffer.print_pending_after_history(cache, !msgs.is_empty());
+    unknown_users
+}
+
+/// Load the history of a buffer, from the message cache first if `use_cache` is set, and run
+/// `on_loaded` on the main thread once the fetched messages are printed
+pub fn load_history<F: FnOnce() + Send + 'static>(
+    buffer: &MessageManager,
+    on_loaded: F,
     fetch_count: i32,
+    use_cache: bool,
 ) {
     let channel = if let Some(channel) = buffer.channel_id() {
         channel
@@ -473,73 +969,267 @@ pub fn load_history(
         return;
     };
     let guild = buffer.guild_id();
+    let account = buffer.account();
+
+    let ctx = match crate::discord::get_ctx(&account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let ctx = &*ctx;
 
     buffer.clear();
     buffer.set_history_loaded();
 
     let buffer_name = buffer.get_name().to_string();
+    let last_read = ctx
+        .cache
+        .read()
+        .read_state
+        .get(&channel)
+        .map(|rs| rs.last_message_id);
 
-    std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+    // Draw whatever we have on disk right away, then only fetch what is newer
+    let (cached, cached_stickers) = if use_cache {
+        message_cache::load(&buffer.get_weechat(), &buffer.account(), channel)
+    } else {
+        Default::default()
+    };
+    let last_cached = cached.last().map(|msg| msg.id);
+    buffer.add_stickers(cached_stickers);
+    let mut unknown_users = print_history(buffer, &ctx.cache, &cached, last_read);
+
+    tasks::spawn_for_channel(&buffer.account(), channel, move || {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
 
-        if let Ok(msgs) = channel.messages(ctx, |retriever| retriever.limit(fetch_count as u64)) {
-            on_main(move |weechat| {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
-                let mut unknown_users = HashSet::new();
-                let buf = match weechat.buffer_manager.get_buffer(&buffer_name) {
-                    Some(buf) => buf,
-                    None => return,
-                };
+        // The newest page also covers the end of the cache, so messages edited or deleted while
+        // offline can be updated
+        let query = format!("limit={}", fetch_count);
+        let (mut msgs, stickers) = match stickers::fetch_messages(ctx, channel, &query) {
+            Ok(page) => page,
+            Err(_) => return,
+        };
+        msgs.sort_by_key(|msg| msg.id);
 
-                if let Some(read_state) = ctx.cache.read().read_state.get(&channel) {
-                    let unread_in_page = msgs.iter().any(|m| m.id == read_state.last_message_id);
-
-                    if unread_in_page {
-                        let mut backlog = true;
-                        for msg in msgs.into_iter().rev() {
-                            unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
-
-                            if backlog {
-                                buf.mark_read();
-                                buf.clear_hotlist();
-                            }
-                            if msg.id == read_state.last_message_id {
-                                backlog = false;
-                            }
-                        }
-                    } else {
-                        buf.mark_read();
-                        buf.clear_hotlist();
-                        for msg in msgs.into_iter().rev() {
-                            unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
-                        }
-                    }
-                } else {
-                    for msg in msgs.into_iter().rev() {
-                        unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
+        on_main(move |weechat| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let ctx = &*ctx;
+            let buf = match weechat.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+
+            buf.add_stickers(stickers);
+            let msgs = match reconcile_cached(&buf, last_cached, msgs) {
+                Reconciled::Append(msgs) => msgs,
+                Reconciled::Replace(msgs) => {
+                    let stickers = buf.stickers();
+                    buf.clear();
+                    buf.add_stickers(stickers);
+                    unknown_users.clear();
+                    msgs
+                },
+            };
+            unknown_users.extend(print_history(&buf, &ctx.cache, &msgs, last_read));
+
+            if let Some(guild) = guild {
+                let msg = json::object! {
+                    "op" =>  OpCode::GetGuildMembers.num(),
+                    "d" => json::object! {
+                        "guild_id" => guild.0.to_string(),
+                        "user_ids" => (unknown_users.iter().map(|id| id.to_string())).collect::<Vec<_>>(),
+                        "nonce" => channel.0.to_string(),
                     }
-                }
-                if let Some(guild) = guild {
-                    let msg = json::object! {
-                        "op" =>  OpCode::GetGuildMembers.num(),
-                        "d" => json::object! {
-                            "guild_id" => guild.0.to_string(),
-                            "user_ids" => (unknown_users.iter().map(|id| id.to_string())).collect::<Vec<_>>(),
-                            "nonce" => channel.0.to_string(),
-                        }
-                    };
-                    ctx.shard
-                        .websocket_message(gateway::Message::Text(msg.to_string()));
-                }
-                let _ = completion_sender.send(());
-            });
+                };
+                ctx.shard
+                    .websocket_message(gateway::Message::Text(msg.to_string()));
+            }
+            on_loaded();
+        });
+    });
+}
+
+enum Reconciled {
+    /// The cached messages are up to date, these are newer
+    Append(Vec<Message>),
+    /// The buffer has to be redrawn with these messages
+    Replace(Vec<Message>),
+}
+
+/// Combine the messages shown from the cache with the newest page of history
+///
+/// Cached messages the page also has are replaced by it if they changed, if the page doesn't reach
+/// back to the cache the messages in between are unknown and only the page is shown
+fn reconcile_cached(
+    buffer: &MessageManager,
+    last_cached: Option<MessageId>,
+    fetched: Vec<Message>,
+) -> Reconciled {
+    let (last_cached, oldest_fetched) = match (last_cached, fetched.first()) {
+        (Some(last_cached), Some(oldest)) if oldest.id <= last_cached => (last_cached, oldest.id),
+        (None, _) => return Reconciled::Append(fetched),
+        _ => return Reconciled::Replace(fetched),
+    };
+    let newest_fetched = fetched.last().map_or(oldest_fetched, |msg| msg.id);
+
+    let shown = buffer.messages();
+    let in_overlap = |msg: &&Message| msg.id >= oldest_fetched && msg.id <= last_cached;
+    let shown_overlap: Vec<_> = shown.iter().filter(in_overlap).collect();
+    let fetched_overlap: Vec<_> = fetched.iter().filter(in_overlap).collect();
+    let unchanged = shown_overlap.len() == fetched_overlap.len()
+        && shown_overlap
+            .iter()
+            .zip(&fetched_overlap)
+            .all(|(shown, fetched)| same_version(shown, fetched));
+
+    let newest_shown = shown.last().map(|msg| msg.id);
+    if unchanged {
+        return Reconciled::Append(
+            fetched
+                .into_iter()
+                .filter(|msg| Some(msg.id) > newest_shown)
+                .collect(),
+        );
+    }
+
+    // Keep older cached messages and messages that arrived since the page was fetched
+    let (older, newer): (Vec<_>, Vec<_>) = shown
+        .into_iter()
+        .filter(|msg| msg.id < oldest_fetched || msg.id > newest_fetched)
+        .partition(|msg| msg.id < oldest_fetched);
+    Reconciled::Replace(older.into_iter().chain(fetched).chain(newer).collect())
+}
+
+/// Whether two copies of a message look the same
+fn same_version(a: &Message, b: &Message) -> bool {
+    a.id == b.id
+        && a.content == b.content
+        && a.edited_timestamp == b.edited_timestamp
+        && a.pinned == b.pinned
+        && a.reactions.len() == b.reactions.len()
+        && a.reactions
+            .iter()
+            .zip(&b.reactions)
+            .all(|(a, b)| a.count == b.count && a.me == b.me)
+}
+
+/// Reload the history of every loaded buffer of an account that started a new session, messages
+/// sent while it was offline are not replayed
+pub fn resync_history(account: &str) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let cache_size = weecord.config.message_cache_size.value() as usize;
+        let fetch_count = weecord.config.message_fetch_count.value();
+        weecord.buffer_manager.save_message_cache(cache_size);
+
+        for name in weecord.buffer_manager.buffer_names() {
+            let buffer = match weecord.buffer_manager.get_buffer(&name) {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            if buffer.account() != account || !buffer.history_loaded() {
+                continue;
+            }
+
+            if utils::pins_for_channel(&buffer).is_some() {
+                load_pin_buffer_history(&buffer);
+            } else {
+                load_history(&buffer, || {}, fetch_count, cache_size > 0);
+            }
+        }
+    });
+}
+
+/// The most messages `load_older_history` loads at once
+pub const MAX_OLDER_MESSAGES: usize = 1000;
+
+/// Fetch `count` (at most `MAX_OLDER_MESSAGES`) messages older than the oldest message in the buffer
+/// and prepend them, paging through the api as needed
+pub fn load_older_history(buffer: &MessageManager, count: usize) {
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => return,
+    };
+    let count = count.min(MAX_OLDER_MESSAGES);
+    let mut before = match buffer.oldest_message_id() {
+        Some(id) => id,
+        None => {
+            let weechat = buffer.get_weechat();
+            let reason = if buffer.history_loaded() {
+                "There are no messages in this buffer to load older messages before"
+            } else {
+                "The history of this buffer has not been loaded yet"
+            };
+            buffer.print(&format!("{}\t{}", weechat.get_prefix("network"), reason));
+            return;
+        },
+    };
+    let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
+
+    tasks::spawn_for_channel(&buffer.account(), channel, move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+
+        let mut msgs = Vec::new();
+        let mut stickers = Stickers::new();
+        // This holds a worker, `count` is capped so it is at most a few pages
+        while msgs.len() < count {
+            let limit = (count - msgs.len()).min(100) as u64;
+            let query = format!("before={}&limit={}", before.0, limit);
+            let (page, page_stickers) = match stickers::fetch_messages(ctx, channel, &query) {
+                Ok(page) => page,
+                Err(e) => {
+                    crate::plugin_print(&format!("Unable to fetch older messages: {}", e));
+                    break;
+                },
+            };
+            stickers.extend(page_stickers);
+            let page_len = page.len() as u64;
+            // Pages are sorted newest first
+            if let Some(oldest) = page.last() {
+                before = oldest.id;
+            }
+            msgs.extend(page);
+            if page_len < limit {
+                break;
+            }
         }
+        msgs.sort_by_key(|msg| msg.id);
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let ctx = &*ctx;
+            let buf = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+
+            // The buffer may have been reloaded while fetching
+            if let Some(oldest) = buf.oldest_message_id() {
+                msgs.retain(|msg| msg.id < oldest);
+            }
+            let prefix = weecord.get_prefix("network").into_owned();
+            if msgs.is_empty() {
+                buf.print(&format!("{}\tNo older messages", prefix));
+            } else {
+                buf.add_stickers(stickers);
+                buf.prepend_messages(&ctx.cache, &msgs);
+                buf.print(&format!("{}\tLoaded {} older messages", prefix, msgs.len()));
+            }
+        });
     });
 }
 
@@ -553,10 +1243,12 @@ pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
         buffer.set_nicks_loaded();
         buffer.enable_nicklist();
 
-        let ctx = match crate::discord::get_ctx() {
+        let account = buffer.account();
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
 
         let recip = channel.recipient.read();
         let cache = ctx.cache.read();
@@ -578,7 +1270,7 @@ pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
                 color: &utils::nick_color(&weechat, &cache.user.name),
                 prefix: &utils::format_user_status_prefix(
                     &weechat,
-                    Some(*crate::command::LAST_STATUS.lock()),
+                    crate::command::LAST_STATUS.lock().get(&account).cloned(),
                 ),
                 ..Default::default()
             },
@@ -600,7 +1292,8 @@ pub fn load_nicks(buffer: &Buffer) {
         return;
     };
 
-    let channel_id = if let Some(channel_id) = buffer.channel_id() {
+    // Threads inherit the members of their parent channel
+    let channel_id = if let Some(channel_id) = buffer.parent_channel_id().or(buffer.channel_id()) {
         channel_id
     } else {
         return;
@@ -609,13 +1302,15 @@ pub fn load_nicks(buffer: &Buffer) {
     buffer.set_nicks_loaded();
     buffer.enable_nicklist();
 
+    let account = buffer.account();
     let sealed_buffer = buffer.seal();
 
-    std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+    tasks::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
 
         let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
 
@@ -626,10 +1321,11 @@ pub fn load_nicks(buffer: &Buffer) {
         drop(guild);
 
         let () = on_main_blocking(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
+            let ctx = &*ctx;
 
             let use_presence = weechat.config.use_presence.value();
 
@@ -738,11 +1434,12 @@ fn add_member_to_nicklist(
     );
 }
 
-pub fn update_nick() {
-    let ctx = match crate::discord::get_ctx() {
+pub fn update_nick(account: &str) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
     let current_user = ctx.cache.read().user.clone();
 
     for guild in current_user.guilds(ctx).expect("Unable to fetch guilds") {
@@ -754,9 +1451,11 @@ pub fn update_nick() {
         };
 
         let channels = guild.id.channels(ctx).expect("Unable to fetch channels");
+        let account = account.to_owned();
         on_main(move |weechat| {
             for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild.id), *channel_id);
+                let string_channel =
+                    utils::buffer_id_for_channel(&account, Some(guild.id), *channel_id);
                 let nick = nick.to_owned();
                 if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
                     buffer.set_localvar("nick", &nick);
@@ -767,7 +1466,7 @@ pub fn update_nick() {
     }
 }
 
-pub fn update_member_nick(old: &Option<Member>, new: &Member) {
+pub fn update_member_nick(account: &str, old: &Option<Member>, new: &Member) {
     let old_nick = if let Some(old) = old.as_ref().map(Member::display_name) {
         old
     } else {
@@ -780,20 +1479,24 @@ pub fn update_member_nick(old: &Option<Member>, new: &Member) {
 
     if old_nick != new_nick {
         let old_nick = old_nick.to_owned().to_string();
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(account) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
 
         let channels = guild_id.channels(ctx).expect("Unable to fetch channels");
 
+        let account = account.to_owned();
         on_main(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
+            let ctx = &*ctx;
             for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild_id), *channel_id);
+                let string_channel =
+                    utils::buffer_id_for_channel(&account, Some(guild_id), *channel_id);
                 if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
                     if let Some(nick) = buffer.search_nick(&old_nick, None) {
                         nick.remove();
@@ -824,3 +1527,63 @@ fn guild_has_crown(guild: &Guild) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn channel(id: u64, kind: u8, position: i64, category: Option<u64>) -> GuildChannel {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "guild_id": "1",
+            "type": kind,
+            "name": format!("channel {}", id),
+            "position": position,
+            "parent_id": category.map(|id| id.to_string()),
+            "permission_overwrites": [],
+            "nsfw": false,
+        }))
+        .unwrap()
+    }
+
+    fn sorted(channels: &[GuildChannel]) -> Vec<u64> {
+        let mut channels: Vec<_> = channels.iter().collect();
+        sort_channels(&mut channels);
+        channels.iter().map(|channel| channel.id.0).collect()
+    }
+
+    #[test]
+    fn channels_without_category_come_first() {
+        let channels = [
+            channel(10, 4, 0, None),
+            channel(11, 0, 0, Some(10)),
+            channel(20, 0, 1, None),
+            channel(21, 0, 0, None),
+        ];
+        assert_eq!(sorted(&channels), vec![21, 20, 10, 11]);
+    }
+
+    #[test]
+    fn categories_are_followed_by_their_channels() {
+        let channels = [
+            channel(11, 0, 1, Some(10)),
+            channel(21, 0, 0, Some(20)),
+            channel(20, 4, 0, None),
+            channel(12, 2, 0, Some(10)),
+            channel(10, 4, 1, None),
+            channel(22, 0, 1, Some(20)),
+        ];
+        assert_eq!(sorted(&channels), vec![20, 21, 22, 10, 12, 11]);
+    }
+
+    #[test]
+    fn equal_positions_are_ordered_by_id() {
+        let channels = [
+            channel(10, 4, 0, None),
+            channel(12, 0, 0, Some(10)),
+            channel(11, 0, 0, Some(10)),
+        ];
+        assert_eq!(sorted(&channels), vec![10, 11, 12]);
+    }
+}
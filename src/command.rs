@@ -1,5 +1,6 @@
 use crate::{
-    buffers, discord, on_main_blocking, plugin_print, upgrade_plugin, utils,
+    buffers, config, discord, message_cache, on_main, on_main_blocking, plugin_print, tasks,
+    upgrade_plugin, utils,
     utils::{BufferExt, ChannelExt, GuildOrChannel},
     weechat_utils::MessageManager,
     Discord,
@@ -7,12 +8,17 @@ use crate::{
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 use serenity::model::{gateway::Activity, user::OnlineStatus};
-use std::{borrow::Cow, collections::VecDeque, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 use weechat::{Buffer, CommandHook, ConfigOption, ReturnCode, Weechat};
 
 lazy_static! {
-    // Tracks the last set status for use in setting the current game presence
-    pub static ref LAST_STATUS: Arc<Mutex<OnlineStatus>> = Arc::new(Mutex::new(OnlineStatus::Online));
+    // Tracks the last set status of each account for use in setting the current game presence
+    pub static ref LAST_STATUS: Arc<Mutex<HashMap<String, OnlineStatus>>> =
+        Arc::new(Mutex::new(HashMap::new()));
 }
 
 pub fn init(weechat: &Weechat) -> Vec<CommandHook<()>> {
@@ -65,13 +71,28 @@ impl<'a> Args<'a> {
             rest: &cmd["/discord ".len() + base.len()..].trim(),
         }
     }
+
+    /// Remove a leading `-account <name>` option, returning the name, which is empty if it is
+    /// missing
+    pub fn take_account(&mut self) -> Option<&'a str> {
+        if self.args.front() != Some(&"-account") {
+            return None;
+        }
+        self.args.pop_front();
+        while self.args.front() == Some(&"") {
+            self.args.pop_front();
+        }
+        let account = self.args.pop_front().unwrap_or_default();
+        self.rest = self.rest["-account".len()..].trim_start()[account.len()..].trim();
+        Some(account)
+    }
 }
 
 fn run_command(buffer: &Buffer, cmd: &str) {
     let weechat = buffer.get_weechat();
     let weecord = upgrade_plugin(&weechat);
 
-    let args = Args::from_cmd(cmd);
+    let mut args = Args::from_cmd(cmd);
 
     if args.base.is_empty() {
         plugin_print("no action provided.");
@@ -79,33 +100,98 @@ fn run_command(buffer: &Buffer, cmd: &str) {
         return;
     }
 
+    // Commands act on the account of the current buffer unless another one is given
+    let account = match args.take_account() {
+        Some("") => {
+            plugin_print("-account needs the name of an account");
+            return;
+        },
+        Some(account) => account.to_owned(),
+        None => buffer.account(),
+    };
+    if !weecord.config.has_account(&account) {
+        plugin_print(&format!("Unknown account \"{}\"", account));
+        return;
+    }
+
+    // Commands on the messages or channel of the current buffer can only use its account
+    let buffer_scoped = match args.base {
+        "pins" | "pinned" | "upload" | "thread" | "threads" | "mute" | "unmute" | "me"
+        | "tableflip" | "unflip" | "shrug" | "spoiler" | "more" | "edits" | "reply" | "retry"
+        | "rehistory" => true,
+        "markread" => args.args.iter().find(|arg| !arg.is_empty()) != Some(&"all"),
+        _ => false,
+    };
+    if buffer_scoped && account != buffer.account() {
+        plugin_print(&format!(
+            "{} acts on the current buffer, which belongs to the account \"{}\"",
+            args.base,
+            buffer.account()
+        ));
+        return;
+    }
+
     match args.base {
-        "connect" => weecord.connect(),
-        "disconnect" => disconnect(weecord),
-        "irc-mode" => irc_mode(weecord),
-        "discord-mode" => discord_mode(weecord),
-        "token" => token(weecord, &args),
-        "autostart" => autostart(weecord),
-        "noautostart" => noautostart(weecord),
+        "connect" => weecord.connect(&account),
+        "disconnect" => disconnect(&account),
+        "reconnect" => {
+            discord::disconnect(&account);
+            weecord.connect(&account);
+        },
+        "account" | "accounts" => accounts(weecord, &args),
+        "irc-mode" => irc_mode(weecord, &account),
+        "discord-mode" => discord_mode(weecord, &account),
+        "token" => token(weecord, &account, &args),
+        "autostart" => autostart(weecord, &account),
+        "noautostart" => noautostart(weecord, &account),
         "query" => {
-            crate::hook::handle_query(&args);
+            crate::hook::handle_query(&account, &args);
         },
         "join" => {
-            join(weecord, &args, true);
+            join(weecord, &account, &args, true);
         },
-        "watch" => watch(weecord, &args),
-        "nowatch" => nowatch(weecord, &args),
-        "watched" => watched(weecord),
-        "autojoin" => autojoin(weecord, &args, buffer),
-        "noautojoin" => noautojoin(weecord, &args),
-        "autojoined" => autojoined(weecord),
-        "status" => status(&args),
+        "watch" => watch(weecord, &account, &args),
+        "nowatch" => nowatch(weecord, &account, &args),
+        "watched" => watched(weecord, &account),
+        "autojoin" => autojoin(weecord, &account, &args, buffer),
+        "noautojoin" => noautojoin(weecord, &account, &args),
+        "autojoined" => autojoined(weecord, &account),
+        "status" => status(&account, &args),
         "pins" | "pinned" => pins(weecord, buffer),
-        "game" => game(&args),
+        "game" => game(&account, &args),
         "upload" => upload(&args, buffer),
+        "thread" | "threads" => threads(&args, buffer),
+        "mute" => mute(&args, buffer, true),
+        "unmute" => mute(&args, buffer, false),
+        "markread" => markread(&account, &args, buffer),
         "me" | "tableflip" | "unflip" | "shrug" | "spoiler" => {
             discord_fmt(args.base, args.rest, buffer)
         },
+        "cache" => cache(weecord, &args),
+        "more" => {
+            let buffer_name = buffer.get_name().to_string();
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                more(weecord, &args, &*buffer);
+            }
+        },
+        "edits" => {
+            let buffer_name = buffer.get_name().to_string();
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                edits(weecord, &args, &*buffer);
+            }
+        },
+        "reply" => {
+            let buffer_name = buffer.get_name().to_string();
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                reply(&args, &*buffer);
+            }
+        },
+        "retry" => {
+            let buffer_name = buffer.get_name().to_string();
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                retry(&args, &*buffer);
+            }
+        },
         "rehistory" => {
             let buffer_name = buffer.get_name().to_string();
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
@@ -118,65 +204,124 @@ fn run_command(buffer: &Buffer, cmd: &str) {
     };
 }
 
-fn disconnect(_weechat: &Weechat) {
-    let mut discord = crate::discord::DISCORD.lock();
-    if discord.is_some() {
-        if let Some(discord) = discord.take() {
-            discord.shutdown();
-        };
-        plugin_print("Disconnected");
+fn disconnect(account: &str) {
+    if discord::disconnect(account) {
+        plugin_print(&format!("Disconnected ({})", account));
     } else {
-        plugin_print("Already disconnected");
+        plugin_print(&format!("{} is already disconnected", account));
+    }
+}
+
+fn accounts(weechat: &Weechat, args: &Args) {
+    let weecord = crate::upgrade_plugin(weechat);
+    let mut opts = args.args.iter().filter(|i| !i.is_empty());
+    match opts.next().cloned().unwrap_or("list") {
+        "list" => {
+            let accounts = weecord.config.account_names();
+            weechat.print("");
+            weechat.print(&format!("Accounts: ({})", accounts.len()));
+            for account in accounts {
+                let state = discord::connection::state(&account);
+                weechat.print(&format!("  {} ({})", account, state));
+            }
+        },
+        "add" => {
+            let name = match opts.next() {
+                Some(name) => name,
+                None => {
+                    plugin_print("account add requires a name");
+                    return;
+                },
+            };
+            if weecord.config.has_account(name) {
+                plugin_print(&format!("Account {} already exists", name));
+                return;
+            }
+            if !config::is_valid_account_name(name) {
+                plugin_print(&format!(
+                    "Invalid account name \"{}\", names start with a letter and only contain \
+                     letters, digits, - and _",
+                    name
+                ));
+                return;
+            }
+            weecord.config.add_account(name);
+            plugin_print(&format!(
+                "Added account {}, set its token with /discord token -account {} <token>",
+                name, name
+            ));
+        },
+        action => plugin_print(&format!("Unknown account action \"{}\"", action)),
     }
 }
 
-fn irc_mode(weechat: &Weechat) {
-    if crate::utils::get_irc_mode(weechat) {
+fn irc_mode(weechat: &Weechat, account: &str) {
+    if crate::utils::get_irc_mode(weechat, account) {
         plugin_print("irc-mode already enabled")
     } else {
         let weecord = crate::upgrade_plugin(weechat);
-        let before = weecord.config.irc_mode.value();
-        let change = weecord.config.irc_mode.set(true);
-        format_option_change("irc_mode", "true", Some(&before), change);
+        weecord.config.with_account(account, |options| {
+            let before = options.irc_mode.value();
+            let change = options.irc_mode.set(true);
+            format_option_change(
+                &options.option_name("irc_mode"),
+                "true",
+                Some(&before),
+                change,
+            );
+        });
         plugin_print("irc-mode enabled")
     }
 }
 
-fn discord_mode(weechat: &Weechat) {
-    if !crate::utils::get_irc_mode(weechat) {
+fn discord_mode(weechat: &Weechat, account: &str) {
+    if !crate::utils::get_irc_mode(weechat, account) {
         plugin_print("discord-mode already enabled")
     } else {
         let weecord = crate::upgrade_plugin(weechat);
-        let before = weecord.config.irc_mode.value();
-        let change = weecord.config.irc_mode.set(false);
-        format_option_change("irc_mode", "false", Some(&before), change);
+        weecord.config.with_account(account, |options| {
+            let before = options.irc_mode.value();
+            let change = options.irc_mode.set(false);
+            format_option_change(
+                &options.option_name("irc_mode"),
+                "false",
+                Some(&before),
+                change,
+            );
+        });
         plugin_print("discord-mode enabled")
     }
 }
 
-fn token(weechat: &Weechat, args: &Args) {
+fn token(weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("token requires an argument");
     } else {
         let weecord = crate::upgrade_plugin(weechat);
         let new_value = args.rest.trim_matches('"');
-        weecord.config.token.set(new_value);
+        weecord
+            .config
+            .with_account(account, |options| options.token.set(new_value));
 
-        plugin_print("Set Discord token");
+        plugin_print(&format!("Set Discord token of {}", account));
     }
 }
 
-fn autostart(weechat: &Weechat) {
-    crate::upgrade_plugin(weechat).config.autostart.set(true);
-    plugin_print("Discord will now load on startup");
+fn autostart(weechat: &Weechat, account: &str) {
+    crate::upgrade_plugin(weechat)
+        .config
+        .with_account(account, |options| options.autostart.set(true));
+    plugin_print(&format!("{} will now connect on startup", account));
 }
 
-fn noautostart(weechat: &Weechat) {
-    crate::upgrade_plugin(weechat).config.autostart.set(false);
-    plugin_print("Discord will not load on startup");
+fn noautostart(weechat: &Weechat, account: &str) {
+    crate::upgrade_plugin(weechat)
+        .config
+        .with_account(account, |options| options.autostart.set(false));
+    plugin_print(&format!("{} will not connect on startup", account));
 }
 
-pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode {
+pub(crate) fn join(_weechat: &Weechat, account: &str, args: &Args, verbose: bool) -> ReturnCode {
     if args.args.is_empty() && verbose {
         plugin_print("join requires an guild name and optional channel name");
         ReturnCode::Error
@@ -188,24 +333,25 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
         };
         let channel_name = args.next();
 
-        let ctx = match discord::get_ctx() {
+        let ctx = match discord::get_ctx(account) {
             Some(ctx) => ctx,
             _ => return ReturnCode::Error,
         };
+        let ctx = &*ctx;
 
         if let Some(channel_name) = channel_name {
             if let Some((guild, channel)) =
                 crate::utils::search_channel(&ctx.cache, guild_name, channel_name)
             {
                 let guild = guild.read();
-                buffers::create_guild_buffer(guild.id, &guild.name);
+                buffers::create_guild_buffer(account, guild.id, &guild.name);
                 // TODO: Add correct nick handling
                 buffers::create_buffer_from_channel(
                     &ctx.cache,
+                    account,
                     &guild.name,
                     &channel.read(),
                     &ctx.cache.read().user.name,
-                    false,
                 );
                 return ReturnCode::OkEat;
             }
@@ -216,7 +362,12 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
 
             let channels = utils::flatten_guilds(&ctx, &[GuildOrChannel::Guild(guild_id)]);
 
-            buffers::create_buffers_from_flat_items(&ctx, &ctx.cache.read().user, &channels);
+            buffers::create_buffers_from_flat_items(
+                &ctx,
+                account,
+                &ctx.cache.read().user,
+                &channels,
+            );
             return ReturnCode::OkEat;
         }
         if verbose {
@@ -227,11 +378,16 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
     }
 }
 
-fn resolve_channel_id(guild_name: &str, channel_name: Option<&str>) -> Option<String> {
-    let ctx = match discord::get_ctx() {
+fn resolve_channel_id(
+    account: &str,
+    guild_name: &str,
+    channel_name: Option<&str>,
+) -> Option<String> {
+    let ctx = match discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return None,
     };
+    let ctx = &*ctx;
 
     if let Some(channel_name) = channel_name {
         if let Some((guild, channel)) =
@@ -269,7 +425,7 @@ fn remove_item(items: Cow<str>, old_item: String) -> String {
     return items.join(",");
 }
 
-fn watch(weechat: &Weechat, args: &Args) {
+fn watch(_weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("watch requires a guild name and optional channel name");
         return;
@@ -281,15 +437,16 @@ fn watch(weechat: &Weechat, args: &Args) {
     };
     let channel_name = args.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
 
-    let weecord = crate::upgrade_plugin(weechat);
-    let new_watched = add_item(weecord.config.watched_channels.value(), new_channel_id);
     let () = on_main_blocking(|weecord| {
-        weecord.config.watched_channels.set(&new_watched);
+        weecord.config.with_account(account, |options| {
+            let new_watched = add_item(options.watched_channels.value(), new_channel_id);
+            options.watched_channels.set(&new_watched);
+        });
     });
     if let Some(channel_name) = channel_name {
         plugin_print(&format!("Now watching {} in {}", guild_name, channel_name))
@@ -298,7 +455,7 @@ fn watch(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn nowatch(weechat: &Weechat, args: &Args) {
+fn nowatch(_weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("nowatch requires a guild name and optional channel name");
         return;
@@ -310,15 +467,16 @@ fn nowatch(weechat: &Weechat, args: &Args) {
     };
     let channel_name = args.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
 
-    let weecord = crate::upgrade_plugin(weechat);
-    let new_watched = remove_item(weecord.config.watched_channels.value(), new_channel_id);
     let () = on_main_blocking(|weecord| {
-        weecord.config.watched_channels.set(&new_watched);
+        weecord.config.with_account(account, |options| {
+            let new_watched = remove_item(options.watched_channels.value(), new_channel_id);
+            options.watched_channels.set(&new_watched);
+        });
     });
     if let Some(channel_name) = channel_name {
         plugin_print(&format!(
@@ -330,16 +488,20 @@ fn nowatch(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn watched(weechat: &Weechat) {
+fn watched(weechat: &Weechat, account: &str) {
     let mut channels = Vec::new();
     let mut guilds = Vec::new();
 
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
 
-    for watched_item in crate::upgrade_plugin(weechat).config.watched_channels() {
+    for watched_item in crate::upgrade_plugin(weechat)
+        .config
+        .watched_channels(account)
+    {
         match watched_item {
             utils::GuildOrChannel::Guild(guild) => guilds.push(guild),
             utils::GuildOrChannel::Channel(guild, channel) => channels.push((guild, channel)),
@@ -380,7 +542,7 @@ fn watched(weechat: &Weechat) {
     }
 }
 
-fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
+fn autojoin(weechat: &Weechat, account: &str, args: &Args, buffer: &Buffer) {
     if args.args.is_empty() {
         plugin_print("autojoin requires a guild name and optional channel name");
         return;
@@ -392,27 +554,32 @@ fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
     };
     let channel_name = opts.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
 
     let weecord = crate::upgrade_plugin(weechat);
-    let new_autojoined = add_item(weecord.config.autojoin_channels.value(), new_channel_id);
-    weecord.config.autojoin_channels.set(&new_autojoined);
+    weecord.config.with_account(account, |options| {
+        let new_autojoined = add_item(options.autojoin_channels.value(), new_channel_id);
+        options.autojoin_channels.set(&new_autojoined);
+    });
 
     if let Some(channel_name) = channel_name {
         plugin_print(&format!(
             "Now autojoining {} in {}",
             guild_name, channel_name
         ));
-        run_command(buffer, &format!("/discord join {}", args.rest));
+        run_command(
+            buffer,
+            &format!("/discord join -account {} {}", account, args.rest),
+        );
     } else {
         plugin_print(&format!("Now autojoining all channels in {}", guild_name))
     }
 }
 
-fn noautojoin(weechat: &Weechat, args: &Args) {
+fn noautojoin(weechat: &Weechat, account: &str, args: &Args) {
     if args.args.is_empty() {
         plugin_print("noautojoin requires a guild name and optional channel name");
         return;
@@ -424,14 +591,16 @@ fn noautojoin(weechat: &Weechat, args: &Args) {
     };
     let channel_name = opts.next();
 
-    let channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let channel_id = match resolve_channel_id(account, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
 
     let weecord = crate::upgrade_plugin(weechat);
-    let new_autojoined = remove_item(weecord.config.autojoin_channels.value(), channel_id);
-    weecord.config.autojoin_channels.set(&new_autojoined);
+    weecord.config.with_account(account, |options| {
+        let new_autojoined = remove_item(options.autojoin_channels.value(), channel_id);
+        options.autojoin_channels.set(&new_autojoined);
+    });
 
     if let Some(channel_name) = channel_name {
         plugin_print(&format!(
@@ -446,16 +615,20 @@ fn noautojoin(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn autojoined(weechat: &Weechat) {
+fn autojoined(weechat: &Weechat, account: &str) {
     let mut channels = Vec::new();
     let mut guilds = Vec::new();
 
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
 
-    for autojoined_item in crate::upgrade_plugin(weechat).config.autojoin_channels() {
+    for autojoined_item in crate::upgrade_plugin(weechat)
+        .config
+        .autojoin_channels(account)
+    {
         match autojoined_item {
             utils::GuildOrChannel::Guild(guild) => guilds.push(guild),
             utils::GuildOrChannel::Channel(guild, channel) => channels.push((guild, channel)),
@@ -496,11 +669,12 @@ fn autojoined(weechat: &Weechat) {
     }
 }
 
-fn status(args: &Args) {
-    let ctx = match crate::discord::get_ctx() {
+fn status(account: &str, args: &Args) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
     let status_str = if args.args.is_empty() {
         "online"
     } else {
@@ -518,7 +692,7 @@ fn status(args: &Args) {
         },
     };
     ctx.set_presence(None, status);
-    *LAST_STATUS.lock() = status;
+    LAST_STATUS.lock().insert(account.to_owned(), status);
     plugin_print(&format!("Status set to {} {:#?}", status_str, status));
 }
 
@@ -530,25 +704,28 @@ fn pins(weechat: &Discord, buffer: &Buffer) {
         None => return,
     };
 
-    let ctx = match crate::discord::get_ctx() {
+    let account = buffer.account();
+    let ctx = match crate::discord::get_ctx(&account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
 
     let channel = match channel_id.to_channel_cached(ctx) {
         Some(ch) => ch,
         None => return,
     };
 
-    buffers::create_pins_buffer(weechat, &channel);
-    buffers::load_pin_buffer_history_for_id(channel.id());
+    buffers::create_pins_buffer(weechat, &account, &channel);
+    buffers::load_pin_buffer_history_for_id(&account, channel.id());
 }
 
-fn game(args: &Args) {
-    let ctx = match crate::discord::get_ctx() {
+fn game(account: &str, args: &Args) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
 
     let activity = if args.args.is_empty() {
         None
@@ -569,7 +746,12 @@ fn game(args: &Args) {
         })
     };
 
-    ctx.set_presence(activity, *LAST_STATUS.lock());
+    let status = LAST_STATUS
+        .lock()
+        .get(account)
+        .cloned()
+        .unwrap_or(OnlineStatus::Online);
+    ctx.set_presence(activity, status);
 }
 
 fn upload(args: &Args, buffer: &Buffer) {
@@ -600,10 +782,11 @@ fn upload(args: &Args, buffer: &Buffer) {
         } else {
             return;
         };
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
         match channel.send_files(ctx, vec![full], |m| m) {
             Ok(_) => plugin_print("File uploaded successfully"),
             Err(e) => {
@@ -615,6 +798,256 @@ fn upload(args: &Args, buffer: &Buffer) {
     }
 }
 
+fn reply(args: &Args, buffer: &MessageManager) {
+    let line = match args.args.front().and_then(|l| l.parse::<usize>().ok()) {
+        Some(line) if line > 0 => line,
+        _ => {
+            plugin_print("reply requires a message number and a message");
+            return;
+        },
+    };
+    let text = args.rest[args.args.front().unwrap().len()..].trim();
+    if text.is_empty() {
+        plugin_print("reply requires a message number and a message");
+        return;
+    }
+
+    crate::hook::send_reply(buffer, line, text);
+}
+
+fn retry(args: &Args, buffer: &MessageManager) {
+    let count = match args.args.front().cloned() {
+        None | Some("") => crate::hook::retry_failed(buffer),
+        Some("clear") => match crate::discord::get_ctx(&buffer.account()) {
+            Some(ctx) => buffer.remove_failed(&ctx.cache),
+            None => return,
+        },
+        Some(_) => {
+            plugin_print("retry only accepts \"clear\"");
+            return;
+        },
+    };
+    if count == 0 {
+        plugin_print("There are no failed messages in this buffer");
+    }
+}
+
+fn threads(args: &Args, buffer: &Buffer) {
+    use crate::discord::threads;
+
+    let parent_id = buffer.parent_channel_id().or_else(|| buffer.channel_id());
+    let (guild_id, parent_id) = match (buffer.guild_id(), parent_id) {
+        (Some(guild_id), Some(parent_id)) => (guild_id, parent_id),
+        _ => {
+            plugin_print("threads are only available in guild channels");
+            return;
+        },
+    };
+    // Only set if the current buffer is a thread
+    let current_thread = buffer.parent_channel_id().and(buffer.channel_id());
+
+    let mut opts = args.args.iter().filter(|i| !i.is_empty());
+    let action = opts
+        .next()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| "list".into());
+    let name = opts.next().map(|n| n.to_string());
+    let buffer_name = buffer.get_name().into_owned();
+    let account = buffer.account();
+
+    tasks::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+
+        if let Err(e) = threads::fetch_active(ctx, &account, guild_id) {
+            plugin_print(&format!("Unable to fetch threads: {}", e));
+            return;
+        }
+
+        if action == "list" {
+            let threads = threads::threads_in(&account, parent_id);
+            on_main(move |weecord| {
+                let buffer = match weecord.buffer_search("weecord", &buffer_name) {
+                    Some(buffer) => buffer,
+                    None => return,
+                };
+                buffer.print("");
+                buffer.print(&format!("Active threads: ({})", threads.len()));
+                for thread in threads {
+                    let joined = if thread.joined { " (joined)" } else { "" };
+                    buffer.print(&format!(
+                        "  {}{}",
+                        parsing::weechat_arg_strip(&thread.name),
+                        joined
+                    ));
+                }
+            });
+            return;
+        }
+
+        let thread = match name {
+            Some(name) => threads::search(&account, parent_id, &name),
+            None => current_thread.and_then(|thread| threads::get(&account, thread)),
+        };
+        let thread = match thread {
+            Some(thread) => thread,
+            None => {
+                plugin_print("Couldn't find thread");
+                return;
+            },
+        };
+
+        match action.as_str() {
+            "open" | "join" => {
+                if action == "join" && !thread.joined {
+                    if let Err(e) = threads::join(ctx, &account, thread.id) {
+                        plugin_print(&format!("Unable to join thread: {}", e));
+                        return;
+                    }
+                }
+                let thread = threads::get(&account, thread.id).unwrap_or(thread);
+                on_main(move |weecord| {
+                    let ctx = match crate::discord::get_ctx(&account) {
+                        Some(ctx) => ctx,
+                        _ => return,
+                    };
+                    let ctx = &*ctx;
+                    buffers::create_buffer_from_thread(
+                        &ctx.cache, weecord, &account, &thread, true,
+                    );
+                });
+            },
+            "leave" => {
+                if let Err(e) = threads::leave(ctx, &account, thread.id) {
+                    plugin_print(&format!("Unable to leave thread: {}", e));
+                    return;
+                }
+                on_main(move |weecord| {
+                    weecord
+                        .buffer_manager
+                        .close_buffer(&utils::buffer_id_for_channel(
+                            &account,
+                            Some(thread.guild_id),
+                            thread.id,
+                        ));
+                });
+            },
+            _ => plugin_print(&format!("Unknown thread action \"{}\"", action)),
+        }
+    });
+}
+
+fn mute(args: &Args, buffer: &Buffer, muted: bool) {
+    let mut opts = args.args.iter().filter(|i| !i.is_empty());
+    let mut next = opts.next();
+    let whole_guild = next == Some(&"guild");
+    if whole_guild {
+        next = opts.next();
+    }
+
+    let guild_id = buffer.guild_id();
+    let channel_id = if whole_guild {
+        None
+    } else {
+        buffer.channel_id()
+    };
+    if guild_id.is_none() && channel_id.is_none() {
+        plugin_print("the current buffer is not a Discord channel or guild");
+        return;
+    }
+    if whole_guild && guild_id.is_none() {
+        plugin_print("the current buffer is not part of a guild");
+        return;
+    }
+
+    let duration = match next {
+        Some(duration) if muted => match parsing::parse_duration(duration) {
+            Some(duration) => Some(duration),
+            None => {
+                plugin_print(&format!(
+                    "Invalid duration \"{}\", expected eg: 30m, 8h or 1d and at most a year",
+                    duration
+                ));
+                return;
+            },
+        },
+        _ => None,
+    };
+
+    let account = buffer.account();
+    tasks::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+
+        if let Err(e) =
+            discord::guild_settings::set_muted(ctx, &account, guild_id, channel_id, muted, duration)
+        {
+            plugin_print(&format!("Unable to update notification settings: {}", e));
+        }
+    });
+}
+
+fn markread(account: &str, args: &Args, buffer: &Buffer) {
+    use crate::discord::{read_state, threads};
+
+    let scope = args
+        .args
+        .iter()
+        .find(|arg| !arg.is_empty())
+        .map(|arg| arg.to_string());
+    let (guild_id, channel_id) = (buffer.guild_id(), buffer.channel_id());
+    if scope.as_deref() == Some("guild") && guild_id.is_none() {
+        plugin_print("the current buffer is not part of a guild");
+        return;
+    }
+
+    let account = account.to_owned();
+    tasks::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+
+        let read = match scope.as_deref() {
+            Some("all") => read_state::unread_channels(ctx, None),
+            Some("guild") => read_state::unread_channels(ctx, guild_id),
+            Some(scope) => {
+                plugin_print(&format!("Unknown markread scope \"{}\"", scope));
+                return;
+            },
+            None => {
+                let channel_id = match channel_id {
+                    Some(channel_id) => channel_id,
+                    None => {
+                        plugin_print("the current buffer is not a Discord channel");
+                        return;
+                    },
+                };
+                channel_id
+                    .to_channel_cached(ctx)
+                    .and_then(|channel| channel.last_message())
+                    .or_else(|| threads::get(&account, channel_id).and_then(|t| t.last_message_id))
+                    .map(|last_message| vec![(channel_id, last_message)])
+                    .unwrap_or_default()
+            },
+        };
+        if read.is_empty() {
+            return;
+        }
+
+        if let Err(e) = read_state::ack_bulk(ctx, &account, &read) {
+            plugin_print(&format!("Unable to mark channels as read: {}", e));
+        }
+    });
+}
+
 // rust-lang/rust#52662 would let this api be improved by accepting option types
 fn format_option_change<T: std::fmt::Display>(
     name: &str,
@@ -659,10 +1092,11 @@ fn discord_fmt(cmd: &str, msg: &str, buffer: &Buffer) {
         return;
     };
 
-    let ctx = match crate::discord::get_ctx() {
+    let ctx = match crate::discord::get_ctx(&buffer.account()) {
         Some(ctx) => ctx,
         _ => return,
     };
+    let ctx = &*ctx;
     let _ = channel.send_message(&ctx.http, |m| m.content(msg));
 }
 
@@ -674,7 +1108,90 @@ fn rehistory(weecord: &Discord, args: &Args, buffer: &MessageManager) {
         .front()
         .and_then(|c| c.parse::<i32>().ok())
         .unwrap_or(default_fetch_count);
-    buffers::load_history(buffer, crossbeam_channel::unbounded().0, count);
+    buffers::load_history(buffer, || {}, count, false);
+}
+
+fn more(weecord: &Discord, args: &Args, buffer: &MessageManager) {
+    let count = match args.args.front() {
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count > 0 && count <= buffers::MAX_OLDER_MESSAGES => count,
+            _ => {
+                plugin_print(&format!(
+                    "more requires a number of messages between 1 and {}",
+                    buffers::MAX_OLDER_MESSAGES
+                ));
+                return;
+            },
+        },
+        None => weecord.config.message_fetch_count.value() as usize,
+    };
+    buffers::load_older_history(buffer, count);
+}
+
+fn edits(weechat: &Weechat, args: &Args, buffer: &MessageManager) {
+    let line = match args.args.front() {
+        Some(line) => match line.parse::<usize>() {
+            Ok(line) if line > 0 => line,
+            _ => {
+                plugin_print("edits requires a message number");
+                return;
+            },
+        },
+        None => 1,
+    };
+
+    let messages = buffer.messages();
+    let msg = match messages.iter().rev().nth(line - 1) {
+        Some(msg) => msg,
+        None => {
+            plugin_print(&format!("Message {} is not loaded", line));
+            return;
+        },
+    };
+
+    let prefix = weechat.get_prefix("network");
+    let history = buffer.edit_history(msg.id);
+    if history.is_empty() {
+        buffer.print(&format!(
+            "{}\tNo edits of message {} have been seen",
+            prefix, line
+        ));
+        return;
+    }
+
+    buffer.print(&format!("{}\tEdit history of message {}:", prefix, line));
+    for version in history.iter().chain(std::iter::once(msg)) {
+        let time = version.edited_timestamp.unwrap_or(version.timestamp);
+        buffer.print(&format!(
+            "{}\t{}{}{} {}",
+            prefix,
+            weechat.color("8"),
+            time.format("%Y-%m-%d %H:%M:%S"),
+            weechat.color("reset"),
+            version.content
+        ));
+    }
+}
+
+fn cache(weecord: &Discord, args: &Args) {
+    match args.args.front().cloned().unwrap_or("stats") {
+        "clear" => match message_cache::clear(weecord) {
+            Ok(removed) => {
+                plugin_print(&format!("Removed cached messages of {} channels", removed))
+            },
+            Err(e) => plugin_print(&format!("Unable to clear message cache: {}", e)),
+        },
+        "stats" => match message_cache::stats(weecord) {
+            Ok((channels, size)) => plugin_print(&format!(
+                "Message cache: {} channels, {} KiB (up to {} messages per channel)",
+                channels,
+                size / 1024,
+                weecord.config.message_cache_size.value()
+            )),
+            Err(e) => plugin_print(&format!("Unable to read message cache: {}", e)),
+        },
+        action => plugin_print(&format!("Unknown cache action \"{}\"", action)),
+    }
 }
 
 const CMD_DESCRIPTION: weechat::CommandDescription = weechat::CommandDescription {
@@ -684,8 +1201,10 @@ Discord from the comfort of your favorite command-line IRC client!
 Source code available at https://github.com/terminal-discord/weechat-discord
 Originally by https://github.com/khyperia/weechat-discord",
     args: "
-    connect
-    disconnect
+    connect [-account <name>]
+    disconnect [-account <name>]
+    reconnect [-account <name>]
+    account [list|add <name>]
     join
     query
     watch
@@ -699,15 +1218,28 @@ Originally by https://github.com/khyperia/weechat-discord",
     noautostart
     token <token>
     upload <file>
+    reply <n> <message>
+    retry [clear]
+    thread [list|open|join|leave] [<thread>]
+    mute [guild] [<duration>]
+    unmute [guild]
+    markread [all|guild]
     me
     tableflip
     unflip
     shrug
     spoiler
-    rehistory",
+    rehistory
+    more [<n>]
+    edits [<n>]
+    cache [clear|stats]",
     args_description: "
+    -account: the account to act on, by default the account of the current buffer (or main), commands on the current
+              buffer can only use its account
     connect: sign in to discord and open chat buffers
     disconnect: sign out of Discord
+    reconnect: disconnect and connect again right away, instead of waiting for the automatic reconnect
+    account: list the configured accounts, or add a new one with its own token and options
     join: join a channel in irc mode by providing guild name and channel name
     query: open a dm with a user (for when there are no discord buffers open)
     irc-mode: enable irc-mode, meaning that weecord will not load all channels like the official client
@@ -722,18 +1254,34 @@ Originally by https://github.com/khyperia/weechat-discord",
     status: set your Discord online status
     token: set Discord login token
     rehistory: reload the history in the current buffer
+    more: load n (default message_load_count, at most 1000) messages older than the oldest one in the current buffer
+    cache: show the size of the on disk message cache, or clear it
+    edits: show the previous versions of the nth most recent message (default 1), as seen since it was loaded
     upload: upload a file to the current channel
+    reply: reply to the nth most recent message in the current channel
+    retry: send the messages of the current buffer that failed to send again, or remove them with clear
+    thread: list the active threads of the current channel, open or join one, or leave the current thread
+    mute: mute the current channel (or its whole guild) on Discord, for a duration like 30m, 8h or 1d (at most a year) or until unmuted
+    unmute: unmute the current channel (or its whole guild) on Discord
+    markread: mark the current channel, every channel of its guild, or all channels as read on Discord
 
 Examples:
   /discord token 123456789ABCDEF
   /discord connect
   /discord autostart
   /discord disconnect
+  /discord account add work
+  /discord token -account work 123456789ABCDEF
+  /discord connect -account work
   /discord upload file.txt
+  /discord reply 2 sounds good
+  /discord mute guild 8h
 ",
     completion:
-"connect || \
-disconnect || \
+"connect -account %(weecord_account_completion) || \
+disconnect -account %(weecord_account_completion) || \
+reconnect -account %(weecord_account_completion) || \
+account list|add || \
 query %(weecord_dm_completion) || \
 watch %(weecord_guild_completion) %(weecord_channel_completion) || \
 nowatch %(weecord_guild_completion) %(weecord_channel_completion) || \
@@ -741,20 +1289,62 @@ watched || \
 autojoined || \
 autojoin %(weecord_guild_completion) %(weecord_channel_completion) || \
 noautojoin %(weecord_guild_completion) %(weecord_channel_completion) || \
-irc-mode || \
-discord-mode || \
+irc-mode -account %(weecord_account_completion) || \
+discord-mode -account %(weecord_account_completion) || \
 pins || \
-token || \
-autostart || \
-noautostart || \
+token -account %(weecord_account_completion) || \
+autostart -account %(weecord_account_completion) || \
+noautostart -account %(weecord_account_completion) || \
 status online|offline|invisible|idle|dnd || \
 game playing|listening|watching || \
 upload %(filename) || \
+reply || \
+retry clear || \
+thread list|open|join|leave %(weecord_thread_completion) || \
+mute guild|15m|1h|8h|1d || \
+unmute guild || \
+markread all|guild || \
 me || \
 tableflip || \
 unflip || \
 shrug || \
 spoiler || \
 rehistory || \
+more || \
+edits || \
+cache clear|stats || \
 join %(weecord_guild_completion) %(weecord_channel_completion)",
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_account_option() {
+        let mut args = Args::from_cmd("/discord connect -account work");
+        assert_eq!(args.take_account(), Some("work"));
+        assert!(args.args.is_empty());
+        assert_eq!(args.rest, "");
+
+        let mut args = Args::from_cmd("/discord join -account  work guild channel");
+        assert_eq!(args.take_account(), Some("work"));
+        assert_eq!(args.args, vec!["guild", "channel"]);
+        assert_eq!(args.rest, "guild channel");
+    }
+
+    #[test]
+    fn missing_account_names_are_empty() {
+        let mut args = Args::from_cmd("/discord connect -account");
+        assert_eq!(args.take_account(), Some(""));
+        let mut args = Args::from_cmd("/discord connect -account ");
+        assert_eq!(args.take_account(), Some(""));
+    }
+
+    #[test]
+    fn arguments_without_account_are_kept() {
+        let mut args = Args::from_cmd("/discord join guild -account");
+        assert_eq!(args.take_account(), None);
+        assert_eq!(args.args, vec!["guild", "-account"]);
+    }
+}
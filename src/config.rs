@@ -1,20 +1,43 @@
 use crate::{utils, utils::GuildOrChannel};
+use std::cell::RefCell;
 use weechat::{
-    BooleanOption, ConfigOption, ConfigSectionInfo, IntegerOption, StringOption, Weechat,
+    BooleanOption, ConfigOption, ConfigSection, ConfigSectionInfo, IntegerOption, StringOption,
+    Weechat,
 };
 
-pub struct Config {
+/// The account configured in the main section, its buffers keep the names they had before other
+/// accounts could be added
+pub const MAIN_ACCOUNT: &str = "main";
+
+/// The options of a single Discord account
+pub struct AccountOptions {
+    pub name: String,
     pub token: StringOption,
     pub watched_channels: StringOption,
     pub autojoin_channels: StringOption,
     pub autostart: BooleanOption,
+    pub irc_mode: BooleanOption,
+    pub highlight_keywords: StringOption,
+}
+
+pub struct Config {
+    pub accounts: StringOption,
     pub use_presence: BooleanOption,
     pub send_typing_events: BooleanOption,
-    pub irc_mode: BooleanOption,
     pub message_fetch_count: IntegerOption,
+    pub message_cache_size: IntegerOption,
+    pub deleted_messages: IntegerOption,
+    pub show_edit_history: BooleanOption,
+    pub emoji_shortcodes: BooleanOption,
+    pub emoji_urls: BooleanOption,
+    pub collapse_categories: BooleanOption,
     pub user_typing_list_max: IntegerOption,
     pub user_typing_list_expanded: BooleanOption,
     pub config: weechat::Config<()>,
+    /// Other accounts have a section each in their own config file, as their sections can only be
+    /// created once the main config has been read
+    account_config: RefCell<weechat::Config<()>>,
+    account_options: RefCell<Vec<AccountOptions>>,
 }
 
 pub fn init(weechat: &Weechat) -> Config {
@@ -27,9 +50,11 @@ pub fn init(weechat: &Weechat) -> Config {
 
     let section = config.new_section(section_info);
 
-    let token = section.new_string_option(
-        "token",
-        "Discord auth token. Supports secure data",
+    let main_account = AccountOptions::new(&section, MAIN_ACCOUNT);
+
+    let accounts = section.new_string_option(
+        "accounts",
+        "Comma separated list of other Discord accounts, their options are in weecord_accounts",
         "",
         "",
         false,
@@ -37,39 +62,70 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
-    let watched_channels = section.new_string_option(
-        "watched_channels",
-        "List of channels to open when a message is received",
-        "",
-        "",
+    let use_presence = section.new_boolean_option(
+        "use_presence",
+        "Show the presence of other users in the nicklist",
+        false,
+        false,
         false,
         None,
         None::<()>,
     );
 
-    let autojoin_channels = section.new_string_option(
-        "autojoin_channels",
-        "List of channels to automatically open on connecting (irc mode only)",
-        "",
-        "",
+    let send_typing_events = section.new_boolean_option(
+        "send_typing_events",
+        "Send typing events to the channel",
+        false,
+        false,
         false,
         None,
         None::<()>,
     );
 
-    let autostart = section.new_boolean_option(
-        "autostart",
-        "Automatically connect to Discord when weechat starts",
+    let message_fetch_count = section.new_integer_option(
+        "message_load_count",
+        "How many messages will be fetched when a buffer is loaded",
+        "",
+        0,
+        100,
+        "25",
+        "25",
         false,
+        None,
+        None::<()>,
+    );
+
+    let message_cache_size = section.new_integer_option(
+        "message_cache_size",
+        "How many messages per channel are kept on disk to draw buffers before history is fetched, \
+         0 disables the cache",
+        "",
+        0,
+        1000,
+        "100",
+        "100",
         false,
+        None,
+        None::<()>,
+    );
+
+    let deleted_messages = section.new_integer_option(
+        "deleted_messages",
+        "How deleted messages are shown: hide = remove them from the buffer, strike = keep their \
+         text struck out, mark = keep them unchanged with a (deleted) marker",
+        "hide|strike|mark",
+        0,
+        0,
+        "hide",
+        "hide",
         false,
         None,
         None::<()>,
     );
 
-    let use_presence = section.new_boolean_option(
-        "use_presence",
-        "Show the presence of other users in the nicklist",
+    let show_edit_history = section.new_boolean_option(
+        "show_edit_history",
+        "Show the previous text of edited messages above their current text",
         false,
         false,
         false,
@@ -77,9 +133,9 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
-    let send_typing_events = section.new_boolean_option(
-        "send_typing_events",
-        "Send typing events to the channel",
+    let emoji_shortcodes = section.new_boolean_option(
+        "emoji_shortcodes",
+        "Show unicode emoji as :shortcode:, for terminals without emoji fonts",
         false,
         false,
         false,
@@ -87,9 +143,9 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
-    let irc_mode = section.new_boolean_option(
-        "irc_mode",
-        r#"Enable "IRC-Mode" where only the channels you choose will be automatically joined"#,
+    let emoji_urls = section.new_boolean_option(
+        "emoji_urls",
+        "Show the image url after custom emoji",
         false,
         false,
         false,
@@ -97,14 +153,11 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
-    let message_fetch_count = section.new_integer_option(
-        "message_load_count",
-        "How many messages will be fetched when a buffer is loaded",
-        "",
-        0,
-        100,
-        "25",
-        "25",
+    let collapse_categories = section.new_boolean_option(
+        "collapse_categories",
+        "Hide the buffers of channels in categories that are collapsed in Discord",
+        false,
+        false,
         false,
         None,
         None::<()>,
@@ -135,22 +188,119 @@ pub fn init(weechat: &Weechat) -> Config {
 
     config.read();
 
+    let mut account_config = weechat.config_new("weecord_accounts", None, None);
+    let mut account_options = vec![main_account];
+    for name in parse_account_names(&accounts.value()) {
+        let section = account_config.new_section(ConfigSectionInfo {
+            name: &name,
+            ..Default::default()
+        });
+        account_options.push(AccountOptions::new(&section, &name));
+    }
+    account_config.read();
+
     Config {
-        token,
-        watched_channels,
-        autojoin_channels,
-        autostart,
+        accounts,
         use_presence,
         send_typing_events,
-        irc_mode,
         message_fetch_count,
+        message_cache_size,
+        deleted_messages,
+        show_edit_history,
+        emoji_shortcodes,
+        emoji_urls,
+        collapse_categories,
         user_typing_list_max,
         user_typing_list_expanded,
         config,
+        account_config: RefCell::new(account_config),
+        account_options: RefCell::new(account_options),
     }
 }
 
-impl Config {
+impl AccountOptions {
+    fn new(section: &ConfigSection, name: &str) -> AccountOptions {
+        let token = section.new_string_option(
+            "token",
+            "Discord auth token. Supports secure data",
+            "",
+            "",
+            false,
+            None,
+            None::<()>,
+        );
+
+        let watched_channels = section.new_string_option(
+            "watched_channels",
+            "List of channels to open when a message is received",
+            "",
+            "",
+            false,
+            None,
+            None::<()>,
+        );
+
+        let autojoin_channels = section.new_string_option(
+            "autojoin_channels",
+            "List of channels to automatically open on connecting (irc mode only)",
+            "",
+            "",
+            false,
+            None,
+            None::<()>,
+        );
+
+        let autostart = section.new_boolean_option(
+            "autostart",
+            "Automatically connect to Discord when weechat starts",
+            false,
+            false,
+            false,
+            None,
+            None::<()>,
+        );
+
+        let irc_mode = section.new_boolean_option(
+            "irc_mode",
+            r#"Enable "IRC-Mode" where only the channels you choose will be automatically joined"#,
+            false,
+            false,
+            false,
+            None,
+            None::<()>,
+        );
+
+        let highlight_keywords = section.new_string_option(
+            "highlight_keywords",
+            "Comma separated list of words that highlight a message like a mention does, in every \
+             guild and channel of the account",
+            "",
+            "",
+            false,
+            None,
+            None::<()>,
+        );
+
+        AccountOptions {
+            name: name.to_owned(),
+            token,
+            watched_channels,
+            autojoin_channels,
+            autostart,
+            irc_mode,
+            highlight_keywords,
+        }
+    }
+
+    /// The full name of an option of this account, eg: `weecord.main.token`
+    pub fn option_name(&self, option: &str) -> String {
+        if self.name == MAIN_ACCOUNT {
+            format!("weecord.main.{}", option)
+        } else {
+            format!("weecord_accounts.{}.{}", self.name, option)
+        }
+    }
+
     pub fn autojoin_channels(&self) -> Vec<GuildOrChannel> {
         self.autojoin_channels
             .value()
@@ -168,4 +318,119 @@ impl Config {
             .filter_map(utils::parse_id)
             .collect()
     }
+
+    pub fn highlight_keywords(&self) -> Vec<String> {
+        self.highlight_keywords
+            .value()
+            .split(',')
+            .map(|keyword| keyword.trim().to_lowercase())
+            .filter(|keyword| !keyword.is_empty())
+            .collect()
+    }
+}
+
+fn parse_account_names(names: &str) -> Vec<String> {
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| is_valid_account_name(name))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Account names are part of buffer names, so they must not look like a guild id or any other
+/// kind of buffer
+pub fn is_valid_account_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        && !["Private", "Pins", MAIN_ACCOUNT].contains(&name)
+}
+
+/// How deleted messages are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletedMessages {
+    Hide,
+    Strike,
+    Mark,
+}
+
+impl Config {
+    pub fn deleted_messages(&self) -> DeletedMessages {
+        match self.deleted_messages.value() {
+            0 => DeletedMessages::Hide,
+            2 => DeletedMessages::Mark,
+            _ => DeletedMessages::Strike,
+        }
+    }
+
+    /// The names of all accounts, the main account first
+    pub fn account_names(&self) -> Vec<String> {
+        self.account_options
+            .borrow()
+            .iter()
+            .map(|account| account.name.clone())
+            .collect()
+    }
+
+    pub fn has_account(&self, account: &str) -> bool {
+        self.account_options
+            .borrow()
+            .iter()
+            .any(|options| options.name == account)
+    }
+
+    /// Run `f` with the options of an account, if it exists
+    pub fn with_account<R>(
+        &self,
+        account: &str,
+        f: impl FnOnce(&AccountOptions) -> R,
+    ) -> Option<R> {
+        self.account_options
+            .borrow()
+            .iter()
+            .find(|options| options.name == account)
+            .map(f)
+    }
+
+    pub fn irc_mode(&self, account: &str) -> bool {
+        self.with_account(account, |options| options.irc_mode.value())
+            .unwrap_or_default()
+    }
+
+    pub fn autojoin_channels(&self, account: &str) -> Vec<GuildOrChannel> {
+        self.with_account(account, AccountOptions::autojoin_channels)
+            .unwrap_or_default()
+    }
+
+    pub fn watched_channels(&self, account: &str) -> Vec<GuildOrChannel> {
+        self.with_account(account, AccountOptions::watched_channels)
+            .unwrap_or_default()
+    }
+
+    pub fn highlight_keywords(&self, account: &str) -> Vec<String> {
+        self.with_account(account, AccountOptions::highlight_keywords)
+            .unwrap_or_default()
+    }
+
+    /// Add an account with its own section of options
+    pub fn add_account(&self, name: &str) {
+        let mut account_config = self.account_config.borrow_mut();
+        let section = account_config.new_section(ConfigSectionInfo {
+            name,
+            ..Default::default()
+        });
+        self.account_options
+            .borrow_mut()
+            .push(AccountOptions::new(&section, name));
+
+        let mut names = parse_account_names(&self.accounts.value());
+        names.push(name.to_owned());
+        self.accounts.set(&names.join(","));
+    }
+
+    pub fn write(&self) {
+        self.config.write();
+        self.account_config.borrow_mut().write();
+    }
 }
@@ -1,40 +1,184 @@
-use super::event_handler::Handler;
+use super::{
+    connection::{self, ConnectionState},
+    event_handler::Handler,
+};
 use crate::Discord;
-use serenity::{client::bridge::gateway::ShardManager, model::gateway::Ready, prelude::*};
+use serenity::{
+    client::bridge::gateway::ShardManager, gateway::GatewayError, model::gateway::Ready, prelude::*,
+};
 use std::{
-    sync::{mpsc, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+/// How long to wait before the first reconnect attempt, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// Connections that lasted at least this long reset the backoff
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
 pub struct DiscordClient {
-    shard_manager: Arc<Mutex<ShardManager>>,
+    /// The shard manager of the current connection, replaced on every reconnect
+    shard_manager: Arc<Mutex<Arc<Mutex<ShardManager>>>>,
+    stopped: Arc<AtomicBool>,
+    /// Wakes the supervisor up when it is waiting to reconnect
+    wake: mpsc::Sender<()>,
 }
 
 impl DiscordClient {
     pub fn start(
         weecord: &Discord,
+        account: &str,
         token: &str,
     ) -> Result<(DiscordClient, mpsc::Receiver<Ready>), serenity::Error> {
         let (tx, rx) = mpsc::channel();
-        let handler = Handler::new(weecord, Arc::new(Mutex::new(tx)));
+        let sender = Arc::new(Mutex::new(tx));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handler = Handler::new(weecord, account, sender.clone(), stopped.clone());
+
+        let client = Client::new(token, handler)?;
 
-        let mut client = Client::new(token, handler)?;
+        let shard_manager = Arc::new(Mutex::new(client.shard_manager.clone()));
+        let (wake, wakeup) = mpsc::channel();
+        let supervisor = Supervisor {
+            account: account.to_owned(),
+            token: token.to_owned(),
+            sender,
+            stopped: stopped.clone(),
+            shard_manager: shard_manager.clone(),
+            wakeup,
+        };
+        thread::spawn(move || supervisor.run(client));
+
+        Ok((
+            DiscordClient {
+                shard_manager,
+                stopped,
+                wake,
+            },
+            rx,
+        ))
+    }
+
+    /// Whether this client is the one a supervisor was started for
+    fn is_supervised_by(&self, stopped: &Arc<AtomicBool>) -> bool {
+        Arc::ptr_eq(&self.stopped, stopped)
+    }
 
-        let shard_manager = client.shard_manager.clone();
-        thread::spawn(move || {
-            if let Err(e) = client.start_shards(1) {
-                crate::on_main(move |weecord| {
-                    weecord.print(&format!(
-                        "discord: An error occurred connecting to discord: {}",
-                        e
+    pub fn shutdown(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.wake.send(());
+        self.shard_manager.lock().lock().shutdown_all();
+    }
+}
+
+/// Runs the shards of an account, starting a new connection whenever they stop until the account
+/// is disconnected
+struct Supervisor {
+    account: String,
+    token: String,
+    sender: Arc<Mutex<mpsc::Sender<Ready>>>,
+    stopped: Arc<AtomicBool>,
+    shard_manager: Arc<Mutex<Arc<Mutex<ShardManager>>>>,
+    wakeup: mpsc::Receiver<()>,
+}
+
+impl Supervisor {
+    fn run(self, mut client: Client) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let result = client.start_shards(1);
+            if self.is_stopped() {
+                return;
+            }
+            if started.elapsed() >= STABLE_CONNECTION {
+                backoff = INITIAL_BACKOFF;
+            }
+
+            match result {
+                Err(serenity::Error::Gateway(GatewayError::InvalidAuthentication)) => {
+                    self.print(&format!(
+                        "discord: The token of {} was rejected, not reconnecting",
+                        self.account
+                    ));
+                    self.give_up();
+                    return;
+                },
+                Err(e) => self.print(&format!(
+                    "discord: An error occurred connecting {} to discord: {}, reconnecting in {}s",
+                    self.account,
+                    e,
+                    backoff.as_secs()
+                )),
+                Ok(()) => self.print(&format!(
+                    "discord: {} lost its connection to discord, reconnecting in {}s",
+                    self.account,
+                    backoff.as_secs()
+                )),
+            }
+            connection::set_state(&self.account, ConnectionState::Reconnecting);
+
+            // Returns early if the account is disconnected in the meantime
+            match self.wakeup.recv_timeout(backoff) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+                _ => return,
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+
+            let (account, sender, stopped) =
+                (&self.account, self.sender.clone(), self.stopped.clone());
+            let handler: Handler = crate::on_main_blocking(move |weecord| {
+                Handler::new(weecord, account, sender, stopped)
+            });
+            client = match Client::new(&self.token, handler) {
+                Ok(client) => client,
+                Err(e) => {
+                    self.print(&format!(
+                        "discord: An error occurred connecting {} to discord: {}",
+                        self.account, e
                     ));
-                });
+                    self.give_up();
+                    return;
+                },
+            };
+
+            // Hold the lock so a shutdown can't slip in between checking and replacing
+            let mut shard_manager = self.shard_manager.lock();
+            if self.is_stopped() {
+                return;
             }
-        });
-        Ok((DiscordClient { shard_manager }, rx))
+            *shard_manager = client.shard_manager.clone();
+            drop(shard_manager);
+        }
     }
 
-    pub fn shutdown(&self) {
-        self.shard_manager.lock().shutdown_all();
+    /// Forget the account like a manual disconnect would, so it can be connected again, unless it
+    /// was already disconnected (and maybe connected again with a new client) in the meantime
+    fn give_up(&self) {
+        // Checked and removed under one lock, so a client connected in between is left alone
+        let client = {
+            let mut clients = super::DISCORD.lock();
+            match clients.get(&self.account) {
+                Some(client) if client.is_supervised_by(&self.stopped) => {
+                    clients.remove(&self.account)
+                },
+                _ => return,
+            }
+        };
+        super::shut_down(&self.account, client);
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn print(&self, msg: &str) {
+        let msg = msg.to_owned();
+        crate::on_main(move |weecord| weecord.print(&msg));
     }
 }
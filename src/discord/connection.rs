@@ -0,0 +1,46 @@
+//! The state of the gateway connection of each account, shown by the `discord_connection` bar item
+use crate::on_main;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// The connection dropped, the session is being resumed or a new one started
+    Reconnecting,
+    Offline,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::Offline => "offline",
+        };
+        f.write_str(state)
+    }
+}
+
+lazy_static! {
+    static ref STATES: Mutex<HashMap<String, ConnectionState>> = Mutex::new(HashMap::new());
+}
+
+/// The connection state of an account, accounts that never connected are offline
+pub fn state(account: &str) -> ConnectionState {
+    STATES
+        .lock()
+        .get(account)
+        .cloned()
+        .unwrap_or(ConnectionState::Offline)
+}
+
+pub fn set_state(account: &str, state: ConnectionState) {
+    let old = STATES.lock().insert(account.to_owned(), state);
+    if old != Some(state) {
+        on_main(|weecord| weecord.update_bar_item("discord_connection"));
+    }
+}
@@ -1,16 +1,25 @@
 use crate::{
-    buffers, discord, on_main, on_main_blocking, utils, weechat_utils::MessageManager, Discord,
+    buffers, discord,
+    discord::connection::{self, ConnectionState},
+    on_main, on_main_blocking, tasks, utils,
+    utils::BufferExt,
+    weechat_utils::{raw, MessageManager},
+    Discord,
 };
 use lazy_static::lazy_static;
 use serenity::{
     cache::CacheRwLock,
+    gateway::ConnectionStage,
     model::{gateway::Ready, prelude::*},
     prelude::*,
 };
 use std::{
     collections::HashMap,
-    sync::{mpsc::Sender, Arc},
-    thread,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
@@ -18,6 +27,7 @@ const MAX_TYPING_EVENTS: usize = 50;
 
 #[derive(Debug, PartialEq, Eq, Ord)]
 pub struct TypingEntry {
+    pub account: String,
     pub channel_id: ChannelId,
     pub guild_id: Option<GuildId>,
     pub user: UserId,
@@ -48,6 +58,11 @@ impl TypingTracker {
         // TODO: Use binary heap or other structure for better performance?
         self.entries.retain(|e| timestamp_now - e.time < 10)
     }
+
+    /// Remove the entries of a disconnected account
+    pub fn clear(&mut self, account: &str) {
+        self.entries.retain(|e| e.account != account)
+    }
 }
 
 lazy_static! {
@@ -57,25 +72,40 @@ lazy_static! {
 }
 
 pub struct Handler {
+    account: String,
     sender: Arc<Mutex<Sender<Ready>>>,
     watched_channels: Vec<utils::GuildOrChannel>,
+    /// Set once the account is disconnected, events still arriving from the old shard are ignored
+    stopped: Arc<AtomicBool>,
 }
 
 impl Handler {
-    pub fn new(weecord: &Discord, sender: Arc<Mutex<Sender<Ready>>>) -> Handler {
-        let watched_channels = weecord.config.watched_channels();
+    pub fn new(
+        weecord: &Discord,
+        account: &str,
+        sender: Arc<Mutex<Sender<Ready>>>,
+        stopped: Arc<AtomicBool>,
+    ) -> Handler {
+        let watched_channels = weecord.config.watched_channels(account);
 
         Handler {
+            account: account.to_owned(),
             sender,
             watched_channels,
+            stopped,
         }
     }
+
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
 }
 
 impl EventHandler for Handler {
-    fn channel_create(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
-        let channel = channel.read();
+    fn channel_create(&self, ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
+        let channel = channel.read().clone();
         print_guild_status_message(
+            &self.account,
             channel.guild_id,
             &format!(
                 "New {} channel `{}` created",
@@ -83,69 +113,162 @@ impl EventHandler for Handler {
                 channel.name()
             ),
         );
+
+        // In irc mode channels are only opened when joined
+        if on_main_blocking(|weecord| weecord.config.irc_mode(&self.account)) {
+            return;
+        }
+        let guild_name = match channel.guild_id.to_guild_cached(&ctx) {
+            Some(guild) => guild.read().name.clone(),
+            None => return,
+        };
+        let account = self.account.clone();
+        tasks::spawn(move || {
+            let nick = buffers::guild_nick(&ctx, channel.guild_id);
+            buffers::create_buffer_from_channel(&ctx.cache, &account, &guild_name, &channel, &nick);
+            buffers::sort_buffers(&account);
+        });
     }
 
     fn channel_delete(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
         let channel = channel.read();
         print_guild_status_message(
+            &self.account,
             channel.guild_id,
             &format!("Channel `{}` deleted", channel.name()),
         );
+        if channel.kind == ChannelType::Category {
+            buffers::close_category_buffer(&self.account, channel.guild_id, channel.id);
+        } else {
+            buffers::mark_channel_deleted(&self.account, Some(channel.guild_id), channel.id);
+        }
     }
 
     fn channel_pins_update(&self, _ctx: Context, pin: ChannelPinsUpdateEvent) {
-        buffers::load_pin_buffer_history_for_id(pin.channel_id);
+        buffers::load_pin_buffer_history_for_id(&self.account, pin.channel_id);
     }
 
     fn channel_update(&self, ctx: Context, old: Option<Channel>, new: Channel) {
         // TODO: Notify more events?
         // * Groups: user learve/join
-        // * guild channel: ?
         match new {
             Channel::Category(new) => {
+                let new = new.read().clone();
+                let guild_id = new
+                    .id
+                    .to_channel_cached(&ctx)
+                    .and_then(Channel::guild)
+                    .map(|ch| ch.read().guild_id);
+                let guild_id = match guild_id {
+                    Some(guild_id) => guild_id,
+                    None => return,
+                };
+
                 // TODO: old doesn't ever seem to be available
                 if let Some(old) = old.and_then(Channel::category) {
-                    let new = new.read();
                     let old = old.read();
-
-                    let guild_id = new
-                        .id
-                        .to_channel_cached(&ctx)
-                        .and_then(Channel::guild)
-                        .map(|ch| ch.read().guild_id);
-
-                    if let Some(guild_id) = guild_id {
-                        if new.name != old.name {
-                            print_guild_status_message(
-                                guild_id,
-                                &format!("Category `{}` renamed to `{}`", old.name, new.name),
-                            );
-                        }
+                    if new.name != old.name {
+                        print_guild_status_message(
+                            &self.account,
+                            guild_id,
+                            &format!("Category `{}` renamed to `{}`", old.name, new.name),
+                        );
                     }
                 }
+
+                // The channels of the category show its name
+                let channels: Vec<_> = match guild_id.to_guild_cached(&ctx) {
+                    Some(guild) => guild
+                        .read()
+                        .channels
+                        .values()
+                        .map(|channel| channel.read().clone())
+                        .filter(|channel| channel.category_id == Some(new.id))
+                        .collect(),
+                    None => return,
+                };
+                buffers::update_category_buffer(&self.account, guild_id, &new);
+                for channel in channels {
+                    buffers::update_channel_buffer(&ctx.cache, &self.account, &channel);
+                }
+                buffers::sort_buffers(&self.account);
             },
             Channel::Guild(new) => {
+                let new = new.read().clone();
                 if let Some(old) = old.and_then(Channel::guild) {
-                    let new = new.read();
                     let old = old.read();
 
                     if new.name != old.name {
                         print_guild_status_message(
+                            &self.account,
                             new.guild_id,
-                            &format!("Category `{}` renamed to `{}`", old.name, new.name),
+                            &format!("Channel `{}` renamed to `{}`", old.name, new.name),
                         );
                     }
                 }
+                buffers::update_channel_buffer(&ctx.cache, &self.account, &new);
+                // The channel may have been moved
+                buffers::sort_buffers(&self.account);
             },
             _ => {},
         }
     }
 
+    fn guild_create(&self, ctx: Context, guild: Guild, is_new: bool) {
+        let guild_name_id = utils::buffer_id_for_guild(&self.account, guild.id);
+        let (exists, irc_mode) = on_main_blocking(|weecord| {
+            let buffer = weecord.buffer_manager.get_buffer(&guild_name_id);
+            if let Some(buffer) = &buffer {
+                if buffer.get_localvar("unavailable").as_deref() == Some("1") {
+                    buffer.set_localvar("unavailable", "0");
+                    buffer.print(&format!(
+                        "{}\tGuild is available again",
+                        weecord.get_prefix("network")
+                    ));
+                }
+            }
+            (buffer.is_some(), weecord.config.irc_mode(&self.account))
+        });
+
+        if is_new && !exists && !irc_mode {
+            let account = self.account.clone();
+            tasks::spawn(move || {
+                buffers::create_guild_buffers(&ctx, &account, guild.id, &guild.name)
+            });
+        }
+    }
+
+    fn guild_delete(
+        &self,
+        _ctx: Context,
+        incomplete: PartialGuild,
+        full: Option<Arc<RwLock<Guild>>>,
+    ) {
+        if let Some(guild) = full {
+            crate::plugin_print(&format!("Left guild {}", guild.read().name));
+        }
+        buffers::close_guild_buffers(&self.account, incomplete.id);
+    }
+
+    fn guild_unavailable(&self, _ctx: Context, guild_id: GuildId) {
+        let guild_name_id = utils::buffer_id_for_guild(&self.account, guild_id);
+        on_main(move |weecord| {
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&guild_name_id) {
+                buffer.set_localvar("unavailable", "1");
+                buffer.print(&format!(
+                    "{}\tGuild is unavailable, this is usually caused by an outage at Discord",
+                    weecord.get_prefix("network")
+                ));
+            }
+        });
+    }
+
     fn guild_member_update(&self, ctx: Context, old: Option<Member>, new: Member) {
-        thread::spawn(move || {
-            buffers::update_member_nick(&old, &new);
+        let account = self.account.clone();
+        tasks::spawn(move || {
+            buffers::update_member_nick(&account, &old, &new);
             if ctx.cache.read().user.id == new.user_id() {
-                buffers::update_nick();
+                buffers::update_nick(&account);
             }
         });
     }
@@ -157,13 +280,13 @@ impl EventHandler for Handler {
         _offline_members: HashMap<UserId, Member>,
         nonce: Option<String>,
     ) {
+        let account = self.account.clone();
         on_main(move |weecord| {
             if let Some(channel_id) = nonce {
                 if let Ok(channel_id) = channel_id.parse::<u64>().map(|id| ChannelId(id)) {
-                    if let Some(buffer) = weecord
-                        .buffer_manager
-                        .get_buffer(&utils::buffer_id_for_channel(Some(guild_id), channel_id))
-                    {
+                    let buffer_name =
+                        utils::buffer_id_for_channel(&account, Some(guild_id), channel_id);
+                    if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
                         buffer.redraw_buffer(&ctx.cache);
                     }
                 }
@@ -172,10 +295,20 @@ impl EventHandler for Handler {
     }
 
     fn message(&self, ctx: Context, msg: Message) {
-        let string_channel = utils::buffer_id_for_channel(msg.guild_id, msg.channel_id);
+        let account = &self.account;
+        let string_channel = utils::buffer_id_for_channel(account, msg.guild_id, msg.channel_id);
         let () = on_main_blocking(move |weecord| {
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&string_channel) {
                 print_message(&ctx.cache, &msg, &buffer);
+            } else if let Some(thread) = discord::threads::get(account, msg.channel_id) {
+                if thread.joined {
+                    buffers::create_buffer_from_thread(
+                        &ctx.cache, &weecord, account, &thread, false,
+                    );
+                    if let Some(buffer) = weecord.buffer_manager.get_buffer(&string_channel) {
+                        print_message(&ctx.cache, &msg, &buffer);
+                    }
+                }
             } else {
                 match msg.channel_id.to_channel(&ctx) {
                     chan @ Ok(Channel::Private(_)) => {
@@ -185,6 +318,7 @@ impl EventHandler for Handler {
                             buffers::create_buffer_from_dm(
                                 &ctx.cache,
                                 &weecord,
+                                account,
                                 chan.unwrap(),
                                 &ctx.cache.read().user.name,
                                 false,
@@ -198,6 +332,7 @@ impl EventHandler for Handler {
                             buffers::create_buffer_from_group(
                                 &ctx.cache,
                                 &weecord,
+                                account,
                                 chan.unwrap(),
                                 &ctx.cache.read().user.name,
                             );
@@ -231,14 +366,14 @@ impl EventHandler for Handler {
                                     format!("@{}", current_user.name)
                                 };
 
-                                buffers::create_guild_buffer(guild.id, &guild.name);
+                                buffers::create_guild_buffer(account, guild.id, &guild.name);
                                 // TODO: Muting
                                 buffers::create_buffer_from_channel(
                                     &ctx.cache,
+                                    account,
                                     &guild.name,
                                     &channel,
                                     &nick,
-                                    false,
                                 );
                                 break;
                             }
@@ -251,7 +386,7 @@ impl EventHandler for Handler {
     }
 
     fn message_delete(&self, ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-        delete_message(&ctx, channel_id, deleted_message_id)
+        delete_message(&ctx, &self.account, channel_id, deleted_message_id)
     }
 
     fn message_delete_bulk(
@@ -261,60 +396,77 @@ impl EventHandler for Handler {
         deleted_messages_ids: Vec<MessageId>,
     ) {
         for message_id in deleted_messages_ids {
-            delete_message(&ctx, channel_id, message_id)
+            delete_message(&ctx, &self.account, channel_id, message_id)
         }
     }
 
     fn message_update(
         &self,
         ctx: Context,
-        _old_if_available: Option<Message>,
+        old_if_available: Option<Message>,
         _new: Option<Message>,
         event: MessageUpdateEvent,
     ) {
-        let (guild_id, channel_id, message_id) = match ctx.cache.read().channel(&event.channel_id) {
-            Some(Channel::Guild(channel)) => {
-                let channel = channel.read();
-                (Some(channel.guild_id), channel.id, event.id)
-            },
-            Some(Channel::Group(channel)) => (None, channel.read().channel_id, event.id),
-            Some(Channel::Private(channel)) => (None, channel.read().id, event.id),
-            _ => return,
-        };
+        let buffer_name =
+            utils::buffer_name_for_channel(&ctx.cache, &self.account, event.channel_id);
 
-        let buffer_name = utils::buffer_id_for_channel(guild_id, channel_id);
+        let account = self.account.clone();
+        on_main_for_channel(&self.account, event.channel_id, move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let ctx = &*ctx;
 
-        thread::spawn(move || {
-            on_main(move |weecord| {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
-                let msg = match channel_id
-                    .messages(ctx, |retriever| retriever.limit(1).around(message_id))
-                    .ok()
-                    .and_then(|mut msgs| msgs.pop())
-                {
-                    Some(msgs) => msgs,
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                // Messages that are not loaded don't need updating
+                let mut msg = match buffer.get_message(&event.id) {
+                    Some(msg) => msg,
                     None => return,
                 };
-
-                if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                    buffer.replace_message(&ctx.cache, &message_id, &msg);
-                }
-            });
+                apply_message_update(&mut msg, &event);
+                buffer.replace_message(&ctx.cache, &event.id, &msg, old_if_available.as_ref());
+            }
         });
     }
 
     fn reaction_add(&self, ctx: Context, reaction: Reaction) {
-        reaction_update(ctx, reaction)
+        reaction_update(ctx, &self.account, reaction, true)
     }
 
     fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
-        reaction_update(ctx, reaction)
+        reaction_update(ctx, &self.account, reaction, false)
+    }
+
+    fn reaction_remove_all(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        removed_from_message_id: MessageId,
+    ) {
+        let buffer_name = utils::buffer_name_for_channel(&ctx.cache, &self.account, channel_id);
+        let account = self.account.clone();
+        on_main_for_channel(&self.account, channel_id, move |weecord| {
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                let ctx = match crate::discord::get_ctx(&account) {
+                    Some(ctx) => ctx,
+                    _ => return,
+                };
+                let ctx = &*ctx;
+                let mut msg = match buffer.get_message(&removed_from_message_id) {
+                    Some(msg) => msg,
+                    None => return,
+                };
+                msg.reactions.clear();
+                buffer.replace_message(&ctx.cache, &removed_from_message_id, &msg, None);
+            }
+        });
     }
 
     fn ready(&self, ctx: Context, ready: Ready) {
+        if self.is_stopped() {
+            return;
+        }
         // Cache seems not to have all fields properly populated
 
         ctx.shard
@@ -339,16 +491,61 @@ impl EventHandler for Handler {
                 }
             }
         }
+        discord::guild_settings::load(&self.account, &ready);
+        discord::read_state::load(&self.account, &ctx.cache);
         if let Some(presence) = ctx.cache.read().presences.get(&ready.user.id) {
-            *crate::command::LAST_STATUS.lock() = presence.status;
+            crate::command::LAST_STATUS
+                .lock()
+                .insert(self.account.clone(), presence.status);
         }
 
-        unsafe {
-            crate::discord::CONTEXT = Some(ctx);
-        }
+        // A new session starts without any of the old subscriptions
+        crate::hook::clear_guild_subscriptions(&self.account);
+
+        discord::set_ctx(&self.account, ctx);
+        connection::set_state(&self.account, ConnectionState::Connected);
+
+        let account = self.account.clone();
+        tasks::spawn(move || {
+            let ctx = match discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                None => return,
+            };
+            if let Err(e) = discord::guild_settings::fetch(&ctx, &account) {
+                crate::plugin_print(&format!(
+                    "Unable to load the notification settings of {}: {}",
+                    account, e
+                ));
+            }
+            match discord::guild_folders::fetch(&ctx, &account) {
+                Ok(_) => buffers::sort_buffers(&account),
+                Err(e) => crate::plugin_print(&format!(
+                    "Unable to load the guild folders of {}: {}",
+                    account, e
+                )),
+            }
+            discord::threads::fetch_all_active(&ctx, &account);
+        });
         let _ = self.sender.lock().send(ready);
     }
 
+    fn resume(&self, _ctx: Context, _resumed: ResumedEvent) {
+        if self.is_stopped() {
+            return;
+        }
+        // Missed events are replayed by discord when a session is resumed
+        connection::set_state(&self.account, ConnectionState::Connected);
+    }
+
+    fn shard_stage_update(&self, _ctx: Context, update: ShardStageUpdateEvent) {
+        if self.is_stopped() || update.new == ConnectionStage::Connected {
+            return;
+        }
+        if connection::state(&self.account) == ConnectionState::Connected {
+            connection::set_state(&self.account, ConnectionState::Reconnecting);
+        }
+    }
+
     fn typing_start(&self, ctx: Context, event: TypingStartEvent) {
         // TODO: Do we want to fetch the user if it isn't cached? (check performance)
         let current_user_id = ctx.cache.read().user.id;
@@ -361,6 +558,7 @@ impl EventHandler for Handler {
             // TODO: Resolve guild nick names
             let mut typing_events = TYPING_EVENTS.lock();
             typing_events.entries.push(TypingEntry {
+                account: self.account.clone(),
                 channel_id: event.channel_id,
                 guild_id: event.guild_id,
                 user: event.user_id,
@@ -377,89 +575,178 @@ impl EventHandler for Handler {
                 weechat.update_bar_item("discord_typing");
             });
 
-            thread::Builder::new()
-                .name("Typing indicator updater".into())
-                .spawn(|| {
-                    // Wait a few seconds, then sweep the list and update the bar item
-                    thread::sleep(Duration::from_secs(10));
-
-                    let mut typing_events = TYPING_EVENTS.lock();
-                    typing_events.sweep();
-                    crate::on_main(|weechat| {
-                        weechat.update_bar_item("discord_typing");
-                    });
-                })
-                .expect("Unable to name thread");
+            // Wait a few seconds, then sweep the list and update the bar item
+            tasks::spawn_after(Duration::from_secs(10), || {
+                let mut typing_events = TYPING_EVENTS.lock();
+                typing_events.sweep();
+                crate::on_main(|weechat| {
+                    weechat.update_bar_item("discord_typing");
+                });
+            });
         }
     }
 
+    fn unknown(&self, ctx: Context, name: String, raw: serde_json::Value) {
+        discord::threads::handle_event(&ctx, &self.account, &name, &raw);
+        discord::guild_settings::handle_event(&self.account, &name, &raw);
+        discord::read_state::handle_event(&ctx, &self.account, &name, &raw);
+    }
+
     fn user_update(&self, _ctx: Context, _old: CurrentUser, _new: CurrentUser) {
-        thread::spawn(|| {
+        let account = self.account.clone();
+        tasks::spawn(move || {
             // TODO: Update nicklist (and/or just rework all nick stuff)
-            buffers::update_nick();
+            buffers::update_nick(&account);
         });
     }
 }
 
-fn delete_message(ctx: &Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-    if let Some(channel) = ctx.cache.read().channels.get(&channel_id) {
-        let guild_id = channel.read().guild_id;
-        let buffer_name = utils::buffer_id_for_channel(Some(guild_id), channel_id);
+fn delete_message(
+    ctx: &Context,
+    account: &str,
+    channel_id: ChannelId,
+    deleted_message_id: MessageId,
+) {
+    let buffer_name = utils::buffer_name_for_channel(&ctx.cache, account, channel_id);
+
+    let ctx_account = account.to_owned();
+    on_main_for_channel(account, channel_id, move |weecord| {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+            let ctx = match discord::get_ctx(&ctx_account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let ctx = &*ctx;
+
+            let mode = weecord.config.deleted_messages();
+            buffer.delete_message(&ctx.cache, &deleted_message_id, mode);
+        }
+    });
+}
 
-        on_main(move |weecord| {
-            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                let ctx = match discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
+fn reaction_update(ctx: Context, account: &str, reaction: Reaction, added: bool) {
+    let is_own = reaction.user_id == ctx.cache.read().user.id;
+    let buffer_name = utils::buffer_name_for_channel(&ctx.cache, account, reaction.channel_id);
+    let ctx_account = account.to_owned();
+    on_main_for_channel(account, reaction.channel_id, move |weecord| {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+            let ctx = match crate::discord::get_ctx(&ctx_account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let ctx = &*ctx;
+            let mut msg = match buffer.get_message(&reaction.message_id) {
+                Some(msg) => msg,
+                None => return,
+            };
+            apply_reaction(&mut msg, &reaction.emoji, added, is_own);
+            buffer.replace_message(&ctx.cache, &reaction.message_id, &msg, None);
+        }
+    });
+}
 
-                buffer.delete_message(&ctx.cache, &deleted_message_id);
-            }
-        });
+/// Apply an update to a buffer on the main thread once the work already queued for its channel,
+/// like loading history, is done
+fn on_main_for_channel<F: 'static + FnOnce(&Discord) + Send>(
+    account: &str,
+    channel: ChannelId,
+    cb: F,
+) {
+    tasks::spawn_for_channel(account, channel, move || on_main(cb));
+}
+
+/// Update a message with the fields present in an update event, absent fields are unchanged
+fn apply_message_update(msg: &mut Message, event: &MessageUpdateEvent) {
+    if let Some(content) = &event.content {
+        msg.content = content.clone();
+    }
+    if let Some(edited_timestamp) = event.edited_timestamp {
+        msg.edited_timestamp = Some(edited_timestamp);
+    }
+    if let Some(pinned) = event.pinned {
+        msg.pinned = pinned;
+    }
+    if let Some(mention_everyone) = event.mention_everyone {
+        msg.mention_everyone = mention_everyone;
+    }
+    if let Some(mentions) = &event.mentions {
+        msg.mentions = mentions.clone();
+    }
+    if let Some(mention_roles) = &event.mention_roles {
+        msg.mention_roles = mention_roles.clone();
+    }
+    if let Some(attachments) = &event.attachments {
+        msg.attachments = attachments.clone();
+    }
+    // Embeds are not parsed by serenity for update events
+    if let Some(embeds) = &event.embeds {
+        if let Ok(embeds) = serde_json::from_value(serde_json::Value::Array(embeds.clone())) {
+            msg.embeds = embeds;
+        }
     }
 }
 
-fn reaction_update(ctx: Context, reaction: Reaction) {
-    let guild_id = match ctx.cache.read().channel(&reaction.channel_id) {
-        Some(Channel::Guild(channel)) => {
-            let channel = channel.read();
-            Some(channel.guild_id)
-        },
-        _ => return,
+/// Add or remove a single reaction from the reaction counts of a message
+fn apply_reaction(msg: &mut Message, emoji: &ReactionType, added: bool, is_own: bool) {
+    let same_emoji = |other: &ReactionType| match (emoji, other) {
+        (ReactionType::Custom { id: a, .. }, ReactionType::Custom { id: b, .. }) => a == b,
+        (ReactionType::Unicode(a), ReactionType::Unicode(b)) => a == b,
+        _ => false,
     };
-    let buffer_name = utils::buffer_id_for_channel(guild_id, reaction.channel_id);
-    thread::spawn(move || {
-        on_main(move |weecord| {
-            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
-                let msg = match reaction
-                    .channel_id
-                    .messages(ctx, |retriever| {
-                        retriever.limit(1).around(reaction.message_id)
-                    })
-                    .ok()
-                    .and_then(|mut msgs| msgs.pop())
-                {
-                    Some(msgs) => msgs,
-                    None => return,
-                };
-                buffer.replace_message(&ctx.cache, &reaction.message_id, &msg);
+    let position = msg
+        .reactions
+        .iter()
+        .position(|r| same_emoji(&r.reaction_type));
+
+    match (position, added) {
+        (Some(index), true) => {
+            let reaction = &mut msg.reactions[index];
+            reaction.count += 1;
+            reaction.me |= is_own;
+        },
+        (None, true) => msg.reactions.push(MessageReaction {
+            count: 1,
+            me: is_own,
+            reaction_type: emoji.clone(),
+        }),
+        (Some(index), false) => {
+            let reaction = &mut msg.reactions[index];
+            reaction.count = reaction.count.saturating_sub(1);
+            if is_own {
+                reaction.me = false;
             }
-        });
-    });
+            if reaction.count == 0 {
+                msg.reactions.remove(index);
+            }
+        },
+        (None, false) => {},
+    }
 }
 
 fn print_message(cache: &CacheRwLock, msg: &Message, buffer: &MessageManager) {
-    let muted = utils::buffer_is_muted(&buffer);
-    let notify = !msg.is_own(cache) && !muted;
+    // Muted buffers filter notifications with their notify level, so mentions still highlight
+    let notify = !msg.is_own(cache);
+    // Messages arriving while their buffer is displayed have been seen, Discord is told once the
+    // user is active in the buffer
+    let current = raw::is_current_buffer(&buffer.get_weechat(), &buffer.get_name());
+    if current || !notify {
+        buffer.set_last_read(msg.id);
+    }
     buffer.add_message(cache, &msg, notify);
+    discord::stickers::fetch_if_needed(&buffer.account(), &buffer.get_name(), msg);
+
+    if notify {
+        if current {
+            discord::read_state::message_shown(&buffer.account(), msg.channel_id, msg.id);
+        } else {
+            let mentioned = buffer.mentions_current_user(cache, msg);
+            discord::read_state::message_received(buffer, msg.channel_id, mentioned);
+        }
+    }
 }
 
-fn print_guild_status_message(guild_id: GuildId, msg: &str) {
-    let buffer_id = utils::buffer_id_for_guild(guild_id);
+fn print_guild_status_message(account: &str, guild_id: GuildId, msg: &str) {
+    let buffer_id = utils::buffer_id_for_guild(account, guild_id);
 
     let msg = msg.to_owned();
     on_main(move |weechat| {
@@ -0,0 +1,92 @@
+//! The guild folders of the user settings, which serenity does not know about
+use super::rest;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::Method;
+use serde_json::Value;
+use serenity::{model::prelude::*, prelude::*};
+use std::{collections::HashMap, sync::Arc};
+
+/// A folder of guilds, guilds outside of folders are in a folder of their own without a name
+#[derive(Debug, Clone)]
+pub struct GuildFolder {
+    pub name: Option<String>,
+    pub guild_ids: Vec<GuildId>,
+}
+
+impl GuildFolder {
+    fn from_value(value: &Value) -> Option<GuildFolder> {
+        let guild_ids = value["guild_ids"]
+            .as_array()?
+            .iter()
+            .filter_map(|id| id.as_str()?.parse().ok())
+            .map(GuildId)
+            .collect();
+        // Only real folders have an id
+        let name = if value["id"].is_null() {
+            None
+        } else {
+            Some(value["name"].as_str().unwrap_or("Folder").to_owned())
+        };
+
+        Some(GuildFolder { name, guild_ids })
+    }
+}
+
+lazy_static! {
+    /// The name of the folder each guild of an account is in
+    static ref FOLDERS: Arc<Mutex<HashMap<(String, GuildId), String>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    /// The guilds of each account in the order of their folders
+    static ref GUILD_ORDER: Arc<Mutex<HashMap<String, Vec<GuildId>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Fetch the guild folders in the order the user arranged them
+pub fn fetch(ctx: &Context, account: &str) -> Result<Vec<GuildFolder>, reqwest::Error> {
+    let settings = rest::request(ctx, Method::GET, "/users/@me/settings", None)?;
+    let folders: Vec<_> = settings["guild_folders"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(GuildFolder::from_value)
+        .collect();
+
+    let mut names = FOLDERS.lock();
+    names.retain(|(folder_account, _), _| folder_account != account);
+    for folder in &folders {
+        if let Some(name) = &folder.name {
+            for &guild_id in &folder.guild_ids {
+                names.insert((account.to_owned(), guild_id), name.clone());
+            }
+        }
+    }
+
+    GUILD_ORDER.lock().insert(
+        account.to_owned(),
+        folders
+            .iter()
+            .flat_map(|folder| folder.guild_ids.iter().cloned())
+            .collect(),
+    );
+
+    Ok(folders)
+}
+
+/// Forget the folders of a disconnected account
+pub fn clear(account: &str) {
+    FOLDERS
+        .lock()
+        .retain(|(folder_account, _), _| folder_account != account);
+    GUILD_ORDER.lock().remove(account);
+}
+
+/// The name of the folder a guild is in
+pub fn folder_of(account: &str, guild: GuildId) -> Option<String> {
+    FOLDERS.lock().get(&(account.to_owned(), guild)).cloned()
+}
+
+/// The guilds of an account in the order of their folders, empty until the folders are fetched
+pub fn guild_order(account: &str) -> Vec<GuildId> {
+    GUILD_ORDER.lock().get(account).cloned().unwrap_or_default()
+}
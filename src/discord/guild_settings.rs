@@ -0,0 +1,485 @@
+//! The notification settings of the current user for each guild and its channels
+//!
+//! Serenity only provides these in the ready event and without mute expiry, role suppression or
+//! collapsed categories, so the full settings are fetched once connected. Later changes arrive as
+//! raw `USER_GUILD_SETTINGS_UPDATE` events
+use super::{rest, threads};
+use crate::{buffers, on_main, utils};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::Method;
+use serde_json::{json, Map, Value};
+use serenity::{cache::CacheRwLock, model::prelude::*, prelude::*};
+use std::{collections::HashMap, sync::Arc};
+
+/// Which messages of a channel should notify
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyLevel {
+    All,
+    Mentions,
+    Nothing,
+}
+
+impl NotifyLevel {
+    /// Discord uses `3` for channels that inherit the level of their category or guild
+    fn from_raw(level: u64) -> Option<NotifyLevel> {
+        match level {
+            0 => Some(NotifyLevel::All),
+            1 => Some(NotifyLevel::Mentions),
+            2 => Some(NotifyLevel::Nothing),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NotifyLevel::All => "all",
+            NotifyLevel::Mentions => "mentions",
+            NotifyLevel::Nothing => "nothing",
+        }
+    }
+}
+
+/// The settings of a guild or of a channel overriding them
+#[derive(Debug, Clone, Copy, Default)]
+struct Override {
+    level: Option<NotifyLevel>,
+    muted: bool,
+    /// Unix timestamp at which a temporary mute ends
+    mute_end: Option<i64>,
+    /// Whether a category is collapsed in the channel list
+    collapsed: bool,
+}
+
+impl Override {
+    fn from_value(value: &Value) -> Override {
+        Override {
+            level: value["message_notifications"]
+                .as_u64()
+                .and_then(NotifyLevel::from_raw),
+            muted: value["muted"].as_bool().unwrap_or_default(),
+            mute_end: value["mute_config"]["end_time"]
+                .as_str()
+                .and_then(|end| DateTime::parse_from_rfc3339(end).ok())
+                .map(|end| end.timestamp()),
+            collapsed: value["collapsed"].as_bool().unwrap_or_default(),
+        }
+    }
+
+    fn is_muted(&self, now: i64) -> bool {
+        self.muted && self.mute_end.map_or(true, |end| end > now)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct GuildSettings {
+    guild: Override,
+    suppress_everyone: bool,
+    suppress_roles: bool,
+    channels: HashMap<ChannelId, Override>,
+}
+
+impl GuildSettings {
+    fn from_value(value: &Value) -> GuildSettings {
+        let channels = value["channel_overrides"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|channel| {
+                let id = channel["channel_id"].as_str()?.parse().ok()?;
+                Some((ChannelId(id), Override::from_value(channel)))
+            })
+            .collect();
+
+        GuildSettings {
+            guild: Override::from_value(value),
+            suppress_everyone: value["suppress_everyone"].as_bool().unwrap_or_default(),
+            suppress_roles: value["suppress_roles"].as_bool().unwrap_or_default(),
+            channels,
+        }
+    }
+}
+
+/// The notification settings in effect for a channel
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelNotifications {
+    pub level: NotifyLevel,
+    pub muted: bool,
+    /// When the mute ends, if it is temporary
+    pub mute_end: Option<i64>,
+}
+
+type AccountSettings = HashMap<Option<GuildId>, GuildSettings>;
+
+lazy_static! {
+    /// Settings by account and guild, the settings of private channels are kept under `None`
+    static ref GUILD_SETTINGS: Arc<Mutex<HashMap<String, AccountSettings>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Replace the known settings of an account with the ones sent in the ready event, until the full
+/// settings have been fetched
+///
+/// Collapsed categories of a previous session are kept so reconnecting doesn't briefly show their
+/// buffers
+pub fn load(account: &str, ready: &Ready) {
+    let mut all_settings = GUILD_SETTINGS.lock();
+    let settings = all_settings.entry(account.to_owned()).or_default();
+    let previous = std::mem::replace(settings, HashMap::new());
+    for guild_settings in ready.user_guild_settings.values() {
+        let mut channels: HashMap<_, _> = guild_settings
+            .channel_overrides
+            .iter()
+            .map(|(&channel_id, channel)| {
+                let channel = Override {
+                    level: NotifyLevel::from_raw(channel.message_notifications as u64),
+                    muted: channel.muted,
+                    mute_end: None,
+                    collapsed: false,
+                };
+                (channel_id, channel)
+            })
+            .collect();
+        let collapsed = previous
+            .get(&guild_settings.guild_id)
+            .into_iter()
+            .flat_map(|previous| &previous.channels)
+            .filter(|(_, channel)| channel.collapsed);
+        for (&channel_id, _) in collapsed {
+            channels.entry(channel_id).or_default().collapsed = true;
+        }
+
+        settings.insert(
+            guild_settings.guild_id,
+            GuildSettings {
+                guild: Override {
+                    level: NotifyLevel::from_raw(guild_settings.message_notifications as u64),
+                    muted: guild_settings.muted,
+                    mute_end: None,
+                    collapsed: false,
+                },
+                suppress_everyone: guild_settings.suppress_everyone,
+                suppress_roles: false,
+                channels,
+            },
+        );
+    }
+}
+
+/// Fetch the full settings of every guild, replacing the incomplete ones from the ready event, and
+/// refresh the buffers of the account
+pub fn fetch(ctx: &Context, account: &str) -> Result<(), reqwest::Error> {
+    let raw = rest::request(ctx, Method::GET, "/users/@me/guilds/settings", None)?;
+    // Newer api versions wrap the settings in an object
+    let entries = if raw["entries"].is_array() {
+        &raw["entries"]
+    } else {
+        &raw
+    };
+
+    let mut guilds = Vec::new();
+    {
+        let mut all_settings = GUILD_SETTINGS.lock();
+        let settings = all_settings.entry(account.to_owned()).or_default();
+        for entry in entries.as_array().into_iter().flatten() {
+            let guild_id = parse_guild_id(entry);
+            settings.insert(guild_id, GuildSettings::from_value(entry));
+            guilds.push(guild_id);
+        }
+    }
+    for guild_id in guilds {
+        refresh_buffers(account, guild_id);
+    }
+    Ok(())
+}
+
+fn parse_guild_id(raw: &Value) -> Option<GuildId> {
+    raw["guild_id"]
+        .as_str()
+        .and_then(|id| id.parse().ok())
+        .map(GuildId)
+}
+
+/// Forget the settings of a disconnected account
+pub fn clear(account: &str) {
+    GUILD_SETTINGS.lock().remove(account);
+}
+
+/// Handle raw `USER_GUILD_SETTINGS_UPDATE` events, sent when the settings change in any client
+pub fn handle_event(account: &str, name: &str, raw: &Value) {
+    if name == "USER_GUILD_SETTINGS_UPDATE" {
+        update(account, raw);
+    }
+}
+
+/// Replace the settings of a guild with raw settings from Discord and refresh its buffers
+pub fn update(account: &str, raw: &Value) {
+    let guild_id = parse_guild_id(raw);
+    GUILD_SETTINGS
+        .lock()
+        .entry(account.to_owned())
+        .or_default()
+        .insert(guild_id, GuildSettings::from_value(raw));
+    refresh_buffers(account, guild_id);
+}
+
+/// Apply the settings of a guild (or of private channels) to its buffers
+fn refresh_buffers(account: &str, guild_id: Option<GuildId>) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+
+        let channels: Vec<ChannelId> = match guild_id {
+            Some(guild_id) => match guild_id.to_guild_cached(&ctx.cache) {
+                Some(guild) => guild.read().channels.keys().cloned().collect(),
+                None => return,
+            },
+            None => {
+                let cache = ctx.cache.read();
+                cache
+                    .private_channels
+                    .keys()
+                    .chain(cache.groups.keys())
+                    .cloned()
+                    .collect()
+            },
+        };
+
+        let mut names: Vec<_> = guild_id
+            .map(|guild_id| utils::buffer_id_for_guild(&account, guild_id))
+            .into_iter()
+            .collect();
+        for channel_id in channels {
+            let threads = threads::threads_in(&account, channel_id);
+            let ids = std::iter::once(channel_id).chain(threads.iter().map(|thread| thread.id));
+            names.extend(ids.map(|id| utils::buffer_id_for_channel(&account, guild_id, id)));
+        }
+        for name in names {
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&name) {
+                buffers::apply_notification_settings(&ctx.cache, &weecord.weechat, &buffer);
+                buffers::apply_collapsed(&ctx.cache, weecord, &buffer);
+            }
+        }
+    });
+}
+
+/// Mute (for `duration` seconds, at most a year, or until unmuted) or unmute a channel, or a whole
+/// guild if no channel is given
+pub fn set_muted(
+    ctx: &Context,
+    account: &str,
+    guild: Option<GuildId>,
+    channel: Option<ChannelId>,
+    muted: bool,
+    duration: Option<u64>,
+) -> Result<(), reqwest::Error> {
+    let mute_config = match (muted, duration.map(|d| d.min(parsing::MAX_DURATION))) {
+        (true, Some(duration)) => json!({
+            "selected_time_window": duration,
+            "end_time": (Utc::now() + chrono::Duration::seconds(duration as i64)).to_rfc3339(),
+        }),
+        (true, None) => json!({ "selected_time_window": -1, "end_time": null }),
+        (false, _) => Value::Null,
+    };
+    let settings = json!({ "muted": muted, "mute_config": mute_config });
+    let body = match channel {
+        Some(channel) => {
+            let mut overrides = Map::new();
+            overrides.insert(channel.0.to_string(), settings);
+            json!({ "channel_overrides": overrides })
+        },
+        None => settings,
+    };
+
+    // Settings of private channels belong to the `@me` guild
+    let guild = guild.map_or_else(|| "@me".to_owned(), |guild| guild.0.to_string());
+    let raw = rest::request(
+        ctx,
+        Method::PATCH,
+        &format!("/users/@me/guilds/{}/settings", guild),
+        Some(&body),
+    )?;
+    update(account, &raw);
+    Ok(())
+}
+
+/// The notification level and mute of a channel, taking its category (or parent channel for
+/// threads) and guild into account, or of the guild itself if no channel is given
+pub fn notifications(
+    cache: &CacheRwLock,
+    account: &str,
+    guild: Option<GuildId>,
+    channel: Option<ChannelId>,
+) -> ChannelNotifications {
+    let now = Utc::now().timestamp();
+
+    let mut chain: Vec<_> = channel.into_iter().collect();
+    if let Some(thread) = channel.and_then(|channel| threads::get(account, channel)) {
+        chain.push(thread.parent_id);
+    }
+    let category = chain
+        .last()
+        .and_then(|&id| id.to_channel_cached(cache))
+        .and_then(Channel::guild)
+        .and_then(|channel| channel.read().category_id);
+    chain.extend(category);
+
+    let all_settings = GUILD_SETTINGS.lock();
+    let settings = all_settings
+        .get(account)
+        .and_then(|settings| settings.get(&guild));
+    let overrides: Vec<Override> = chain
+        .iter()
+        .filter_map(|id| settings.and_then(|settings| settings.channels.get(id)))
+        .chain(settings.map(|settings| &settings.guild))
+        .cloned()
+        .collect();
+
+    let level = overrides
+        .iter()
+        .find_map(|settings| settings.level)
+        .unwrap_or_else(|| default_level(cache, guild));
+
+    let mutes: Vec<_> = overrides.iter().filter(|o| o.is_muted(now)).collect();
+    // The channel stays muted until the last temporary mute ends
+    let mute_end = if mutes.iter().any(|o| o.mute_end.is_none()) {
+        None
+    } else {
+        mutes.iter().filter_map(|o| o.mute_end).max()
+    };
+
+    ChannelNotifications {
+        level,
+        muted: !mutes.is_empty(),
+        mute_end,
+    }
+}
+
+/// The level set by the guild for members that have not changed it
+fn default_level(cache: &CacheRwLock, guild: Option<GuildId>) -> NotifyLevel {
+    let guild = match guild.and_then(|guild| guild.to_guild_cached(cache)) {
+        Some(guild) => guild,
+        None => return NotifyLevel::All,
+    };
+    let level = guild.read().default_message_notifications;
+    match level {
+        DefaultMessageNotificationLevel::Mentions => NotifyLevel::Mentions,
+        _ => NotifyLevel::All,
+    }
+}
+
+/// Run `f` with the settings of a guild, if they are known
+fn with_guild<R>(account: &str, guild: GuildId, f: impl FnOnce(&GuildSettings) -> R) -> Option<R> {
+    GUILD_SETTINGS
+        .lock()
+        .get(account)
+        .and_then(|settings| settings.get(&Some(guild)))
+        .map(f)
+}
+
+/// Whether `@everyone` and `@here` mentions should not highlight in a guild
+pub fn suppress_everyone(account: &str, guild: GuildId) -> bool {
+    with_guild(account, guild, |settings| settings.suppress_everyone).unwrap_or_default()
+}
+
+/// Whether role mentions should not highlight in a guild
+pub fn suppress_roles(account: &str, guild: GuildId) -> bool {
+    with_guild(account, guild, |settings| settings.suppress_roles).unwrap_or_default()
+}
+
+/// Whether a category is collapsed in the channel list
+///
+/// Serenity drops this from the ready event, it is known once the full settings are fetched
+pub fn category_collapsed(account: &str, guild: GuildId, category: ChannelId) -> bool {
+    with_guild(account, guild, |settings| {
+        settings
+            .channels
+            .get(&category)
+            .map_or(false, |category| category.collapsed)
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inherited_level_is_none() {
+        assert_eq!(NotifyLevel::from_raw(0), Some(NotifyLevel::All));
+        assert_eq!(NotifyLevel::from_raw(1), Some(NotifyLevel::Mentions));
+        assert_eq!(NotifyLevel::from_raw(2), Some(NotifyLevel::Nothing));
+        assert_eq!(NotifyLevel::from_raw(3), None);
+    }
+
+    #[test]
+    fn parses_overrides() {
+        let settings = Override::from_value(&json!({
+            "message_notifications": 1,
+            "muted": true,
+            "mute_config": { "end_time": "2021-01-01T00:00:00+00:00" },
+            "collapsed": true,
+        }));
+        assert_eq!(settings.level, Some(NotifyLevel::Mentions));
+        assert!(settings.muted);
+        assert_eq!(settings.mute_end, Some(1_609_459_200));
+        assert!(settings.collapsed);
+
+        let settings = Override::from_value(&json!({ "message_notifications": 3 }));
+        assert_eq!(settings.level, None);
+        assert!(!settings.muted);
+        assert_eq!(settings.mute_end, None);
+        assert!(!settings.collapsed);
+    }
+
+    #[test]
+    fn temporary_mutes_end() {
+        let settings = Override {
+            muted: true,
+            mute_end: Some(100),
+            ..Override::default()
+        };
+        assert!(settings.is_muted(99));
+        assert!(!settings.is_muted(100));
+
+        let settings = Override {
+            muted: true,
+            ..Override::default()
+        };
+        assert!(settings.is_muted(i64::max_value()));
+        assert!(!Override::default().is_muted(0));
+    }
+
+    #[test]
+    fn parses_guild_settings() {
+        let raw = json!({
+            "guild_id": "41771983423143936",
+            "message_notifications": 2,
+            "suppress_everyone": true,
+            "channel_overrides": [
+                { "channel_id": "41771983423143937", "message_notifications": 0, "muted": true },
+                { "channel_id": "invalid", "muted": true },
+            ],
+        });
+        assert_eq!(parse_guild_id(&raw), Some(GuildId(41_771_983_423_143_936)));
+
+        let settings = GuildSettings::from_value(&raw);
+        assert_eq!(settings.guild.level, Some(NotifyLevel::Nothing));
+        assert!(settings.suppress_everyone);
+        assert!(!settings.suppress_roles);
+        assert_eq!(settings.channels.len(), 1);
+        let channel = &settings.channels[&ChannelId(41_771_983_423_143_937)];
+        assert_eq!(channel.level, Some(NotifyLevel::All));
+        assert!(channel.muted);
+    }
+
+    #[test]
+    fn private_settings_have_no_guild() {
+        assert_eq!(parse_guild_id(&json!({ "guild_id": null })), None);
+    }
+}
@@ -1,48 +1,107 @@
 use self::client::DiscordClient;
-use crate::Discord;
+use crate::{on_main, Discord};
 use lazy_static::lazy_static;
 use serenity::{client::Context, prelude::Mutex};
-use std::{sync::Arc, thread};
+use std::{collections::HashMap, sync::Arc, thread};
 
 mod client;
+pub mod connection;
 mod event_handler;
 pub mod formatting;
+pub mod guild_folders;
+pub mod guild_settings;
+pub mod read_state;
+mod rest;
+pub mod stickers;
+pub mod threads;
 
 pub use event_handler::TYPING_EVENTS;
 
-pub static mut CONTEXT: Option<Context> = None;
+lazy_static! {
+    /// The clients of the connected accounts
+    pub(crate) static ref DISCORD: Arc<Mutex<HashMap<String, DiscordClient>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    /// The context of each account that received its ready event, replaced when a new session is
+    /// started
+    static ref CONTEXTS: Mutex<HashMap<String, Arc<Context>>> = Mutex::new(HashMap::new());
+}
 
-pub fn get_ctx() -> Option<&'static Context> {
-    unsafe { CONTEXT.as_ref() }
+pub fn get_ctx(account: &str) -> Option<Arc<Context>> {
+    CONTEXTS.lock().get(account).cloned()
 }
 
-lazy_static! {
-    pub(crate) static ref DISCORD: Arc<Mutex<Option<DiscordClient>>> = Arc::new(Mutex::new(None));
+pub(crate) fn set_ctx(account: &str, ctx: Context) {
+    CONTEXTS.lock().insert(account.to_owned(), Arc::new(ctx));
+}
+
+pub fn is_connected(account: &str) -> bool {
+    DISCORD.lock().contains_key(account)
 }
 
-pub fn init(weecord: &Discord, token: &str, irc_mode: bool) {
-    let (discord_client, events) = match DiscordClient::start(weecord, token) {
+pub fn init(weecord: &Discord, account: &str, token: &str, irc_mode: bool) {
+    connection::set_state(account, connection::ConnectionState::Connecting);
+    let (discord_client, events) = match DiscordClient::start(weecord, account, token) {
         Ok(d) => d,
         Err(e) => {
+            connection::set_state(account, connection::ConnectionState::Offline);
             // Cannot use plugin_print because we haven't finished init
             weecord.print(&format!(
-                "discord: An error occurred connecting to discord: {}",
-                e
+                "discord: An error occurred connecting {} to discord: {}",
+                account, e
             ));
             return;
         },
     };
 
+    let name = account.to_owned();
     thread::spawn(move || {
-        if let Ok(ready) = events.recv() {
-            crate::plugin_print("Discord connected");
+        // Resumed sessions replay what was missed, a ready event means a new session was started
+        // and everything has to be loaded again
+        for (i, ready) in events.iter().enumerate() {
+            if i == 0 {
+                crate::plugin_print(&format!("Discord connected ({})", name));
+            } else {
+                crate::plugin_print(&format!("Discord reconnected ({})", name));
+            }
             if irc_mode {
-                crate::buffers::create_autojoin_buffers(&ready);
+                crate::buffers::create_autojoin_buffers(&name, &ready);
             } else {
-                crate::buffers::create_buffers(&ready);
+                crate::buffers::create_buffers(&name, &ready);
+            }
+            if i > 0 {
+                crate::buffers::resync_history(&name);
             }
         }
     });
 
-    *DISCORD.lock() = Some(discord_client);
+    DISCORD.lock().insert(account.to_owned(), discord_client);
+}
+
+/// Disconnect an account and forget everything known about it, returning whether it was connected
+pub fn disconnect(account: &str) -> bool {
+    let client = DISCORD.lock().remove(account);
+    shut_down(account, client)
+}
+
+/// Shut down a client that was removed from `DISCORD` and forget everything known about its account
+fn shut_down(account: &str, client: Option<DiscordClient>) -> bool {
+    CONTEXTS.lock().remove(account);
+    connection::set_state(account, connection::ConnectionState::Offline);
+
+    TYPING_EVENTS.lock().clear(account);
+    guild_folders::clear(account);
+    guild_settings::clear(account);
+    read_state::clear(account);
+    threads::clear(account);
+    crate::command::LAST_STATUS.lock().remove(account);
+    crate::hook::clear_guild_subscriptions(account);
+    on_main(|weecord| weecord.update_bar_item("discord_typing"));
+
+    match client {
+        Some(client) => {
+            client.shutdown();
+            true
+        },
+        None => false,
+    }
 }
@@ -0,0 +1,291 @@
+//! Keeps read states in sync with other clients, serenity neither handles the raw `MESSAGE_ACK`
+//! event nor updates its cached read states
+use super::{rest, threads};
+use crate::{
+    on_main, tasks, utils,
+    utils::{BufferExt, ChannelExt},
+    weechat_utils::raw,
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::Method;
+use serde_json::{json, Value};
+use serenity::{cache::CacheRwLock, model::prelude::*, prelude::*};
+use std::{collections::HashMap, sync::Arc};
+use weechat::Buffer;
+
+/// What arrived in a channel since it was last read
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unread {
+    /// Messages seen since connecting, older unread messages are not known
+    pub messages: u64,
+    pub mentions: u64,
+}
+
+lazy_static! {
+    /// The newest message shown in the current buffer of each channel of an account that has not
+    /// been acknowledged yet
+    static ref UNACKED: Arc<Mutex<HashMap<(String, ChannelId), MessageId>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    /// Channels of each account with unread messages
+    static ref UNREAD: Arc<Mutex<HashMap<(String, ChannelId), Unread>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Start counting from the mention counts Discord keeps for each channel of an account
+pub fn load(account: &str, cache: &CacheRwLock) {
+    let mut unread = UNREAD.lock();
+    unread.retain(|(unread_account, _), _| unread_account != account);
+    for (&channel, read_state) in &cache.read().read_state {
+        if read_state.mention_count > 0 {
+            let mentions = read_state.mention_count as u64;
+            unread.insert(
+                (account.to_owned(), channel),
+                Unread {
+                    messages: 0,
+                    mentions,
+                },
+            );
+        }
+    }
+}
+
+/// Forget the unread counts and pending acknowledgements of a disconnected account
+pub fn clear(account: &str) {
+    UNREAD
+        .lock()
+        .retain(|(unread_account, _), _| unread_account != account);
+    UNACKED
+        .lock()
+        .retain(|(ack_account, _), _| ack_account != account);
+    on_main(|weecord| weecord.update_bar_item("discord_unread"));
+}
+
+/// The unread messages and mentions of a channel
+pub fn unread(account: &str, channel: ChannelId) -> Unread {
+    UNREAD
+        .lock()
+        .get(&(account.to_owned(), channel))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The unread messages and mentions of all channels of all accounts
+pub fn total_unread() -> Unread {
+    UNREAD
+        .lock()
+        .values()
+        .fold(Unread::default(), |total, unread| Unread {
+            messages: total.messages + unread.messages,
+            mentions: total.mentions + unread.mentions,
+        })
+}
+
+/// Count a message by someone else that arrived in a buffer that is not displayed
+pub fn message_received(buffer: &Buffer, channel: ChannelId, mentioned: bool) {
+    {
+        let mut unread = UNREAD.lock();
+        let unread = unread.entry((buffer.account(), channel)).or_default();
+        unread.messages += 1;
+        if mentioned {
+            unread.mentions += 1;
+        }
+    }
+    update_buffer(buffer, channel);
+}
+
+/// Show the unread counts of a channel in the `unread` and `mentions` localvars of its buffer
+pub fn update_buffer(buffer: &Buffer, channel: ChannelId) {
+    let unread = unread(&buffer.account(), channel);
+    buffer.set_localvar("unread", &unread.messages.to_string());
+    buffer.set_localvar("mentions", &unread.mentions.to_string());
+    buffer.get_weechat().update_bar_item("discord_unread");
+}
+
+fn parse_id(value: &Value) -> Option<u64> {
+    value.as_str().and_then(|id| id.parse().ok())
+}
+
+/// Handle raw `MESSAGE_ACK` events, sent when a channel is read in any client
+pub fn handle_event(ctx: &Context, account: &str, name: &str, raw: &Value) {
+    if name != "MESSAGE_ACK" {
+        return;
+    }
+
+    if let (Some(channel_id), Some(message_id)) =
+        (parse_id(&raw["channel_id"]), parse_id(&raw["message_id"]))
+    {
+        set_read(ctx, account, ChannelId(channel_id), MessageId(message_id));
+    }
+}
+
+/// Acknowledge a message right away
+pub fn ack(
+    ctx: &Context,
+    account: &str,
+    channel: ChannelId,
+    message: MessageId,
+) -> serenity::Result<()> {
+    channel.ack_message(ctx, message)?;
+    set_read(ctx, account, channel, message);
+    Ok(())
+}
+
+/// Remember a message that was shown in the current buffer, it is acknowledged once the user is
+/// active in the buffer
+pub fn message_shown(account: &str, channel: ChannelId, message: MessageId) {
+    let mut unacked = UNACKED.lock();
+    let newest = unacked
+        .entry((account.to_owned(), channel))
+        .or_insert(message);
+    *newest = (*newest).max(message);
+}
+
+/// Acknowledge the messages shown in the buffer of a channel since it was last read
+pub fn ack_shown(account: &str, channel: ChannelId) {
+    let message = match UNACKED.lock().remove(&(account.to_owned(), channel)) {
+        Some(message) => message,
+        None => return,
+    };
+
+    let task_account = account.to_owned();
+    tasks::spawn(move || {
+        let account = task_account;
+        if let Some(ctx) = super::get_ctx(&account) {
+            let _ = ack(&ctx, &account, channel, message);
+        }
+    });
+}
+
+/// Acknowledge the newest message of many channels at once
+pub fn ack_bulk(
+    ctx: &Context,
+    account: &str,
+    read: &[(ChannelId, MessageId)],
+) -> Result<(), reqwest::Error> {
+    // Discord limits how many read states can be updated in one request
+    for chunk in read.chunks(100) {
+        let read_states: Vec<_> = chunk
+            .iter()
+            .map(|(channel, message)| {
+                json!({
+                    "channel_id": channel.0.to_string(),
+                    "message_id": message.0.to_string(),
+                })
+            })
+            .collect();
+        rest::request(
+            ctx,
+            Method::POST,
+            "/read-states/ack-bulk",
+            Some(&json!({ "read_states": read_states })),
+        )?;
+
+        for &(channel, message) in chunk {
+            set_read(ctx, account, channel, message);
+        }
+    }
+    Ok(())
+}
+
+/// The channels with messages that have not been read yet, and their newest message
+pub fn unread_channels(ctx: &Context, guild: Option<GuildId>) -> Vec<(ChannelId, MessageId)> {
+    let cache = ctx.cache.read();
+    let channels: Vec<(ChannelId, Option<MessageId>)> = match guild {
+        Some(guild) => cache
+            .guilds
+            .get(&guild)
+            .map(|guild| {
+                guild
+                    .read()
+                    .channels
+                    .values()
+                    .map(|channel| {
+                        let channel = channel.read();
+                        (channel.id, channel.last_message_id)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        None => cache
+            .channels
+            .values()
+            .map(|channel| {
+                let channel = channel.read();
+                (channel.id, channel.last_message_id)
+            })
+            .chain(cache.private_channels.values().map(|channel| {
+                let channel = channel.read();
+                (channel.id, channel.last_message_id)
+            }))
+            .chain(cache.groups.values().map(|channel| {
+                let channel = channel.read();
+                (channel.channel_id, channel.last_message_id)
+            }))
+            .collect(),
+    };
+
+    channels
+        .into_iter()
+        .filter_map(|(channel, last_message)| {
+            let last_message = last_message?;
+            let read_state = cache.read_state.get(&channel)?;
+            if read_state.last_message_id < last_message {
+                Some((channel, last_message))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Record a channel as read up to a message, clearing its unread counts and the hotlist of its
+/// buffer if nothing newer is left
+pub fn set_read(ctx: &Context, account: &str, channel: ChannelId, message: MessageId) {
+    {
+        let mut unacked = UNACKED.lock();
+        let key = (account.to_owned(), channel);
+        if unacked
+            .get(&key)
+            .map_or(false, |&unacked| unacked <= message)
+        {
+            unacked.remove(&key);
+        }
+    }
+    if let Some(read_state) = ctx.cache.write().read_state.get_mut(&channel) {
+        if read_state.last_message_id < message {
+            read_state.last_message_id = message;
+        }
+    }
+
+    let newest = channel
+        .to_channel_cached(ctx)
+        .and_then(|channel| channel.last_message())
+        .or_else(|| threads::get(account, channel).and_then(|thread| thread.last_message_id));
+    let buffer_name = utils::buffer_name_for_channel(&ctx.cache, account, channel);
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let buffer = weecord.buffer_manager.get_buffer(&buffer_name);
+        if let Some(buffer) = &buffer {
+            buffer.set_last_read(message);
+        }
+
+        let newest = buffer
+            .as_ref()
+            .and_then(|buffer| buffer.last_message_id())
+            .max(newest);
+        if newest.map_or(false, |newest| newest > message) {
+            return;
+        }
+
+        UNREAD.lock().remove(&(account, channel));
+        weecord.update_bar_item("discord_unread");
+        if let Some(buffer) = buffer {
+            if !raw::is_current_buffer(&weecord.weechat, &buffer_name) {
+                buffer.mark_read();
+            }
+            buffer.clear_hotlist();
+            update_buffer(&buffer, channel);
+        }
+    });
+}
@@ -0,0 +1,34 @@
+//! Requests to api endpoints that serenity does not support
+use lazy_static::lazy_static;
+use reqwest::{Client, Method, StatusCode};
+use serde_json::Value;
+use serenity::prelude::Context;
+
+const API_BASE: &str = "https://discord.com/api/v9";
+
+lazy_static! {
+    static ref CLIENT: Client = Client::new();
+}
+
+/// Make an authenticated request, returning the decoded body, or `Value::Null` if the response
+/// has no content
+pub fn request(
+    ctx: &Context,
+    method: Method,
+    path: &str,
+    body: Option<&Value>,
+) -> Result<Value, reqwest::Error> {
+    let mut request = CLIENT
+        .request(method, &format!("{}{}", API_BASE, path))
+        .header("Authorization", ctx.http.token.as_str());
+    if let Some(body) = body {
+        request = request.json(body);
+    }
+
+    let mut response = request.send()?.error_for_status()?;
+    if response.status() == StatusCode::NO_CONTENT {
+        Ok(Value::Null)
+    } else {
+        response.json()
+    }
+}
@@ -0,0 +1,159 @@
+//! Serenity does not know about stickers, so they are read from the raw messages of history pages
+//! and fetched for new messages that look empty
+use super::rest;
+use crate::{on_main, tasks};
+use reqwest::Method;
+use serde_json::{json, Value};
+use serenity::{model::prelude::*, prelude::*};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct Sticker {
+    pub id: u64,
+    pub name: String,
+    format_type: u64,
+}
+
+/// The stickers of the messages that have any
+pub type Stickers = HashMap<MessageId, Vec<Sticker>>;
+
+impl Sticker {
+    fn from_value(value: &Value) -> Option<Sticker> {
+        Some(Sticker {
+            id: value["id"].as_str()?.parse().ok()?,
+            name: value["name"].as_str()?.to_owned(),
+            format_type: value["format_type"].as_u64().unwrap_or(1),
+        })
+    }
+
+    fn to_value(&self) -> Value {
+        json!({
+            "id": self.id.to_string(),
+            "name": self.name,
+            "format_type": self.format_type,
+        })
+    }
+
+    /// The url of the sticker image, or animation data for lottie stickers
+    pub fn url(&self) -> String {
+        match self.format_type {
+            3 => format!("https://cdn.discordapp.com/stickers/{}.json", self.id),
+            4 => format!("https://media.discordapp.net/stickers/{}.gif", self.id),
+            _ => format!("https://cdn.discordapp.com/stickers/{}.png", self.id),
+        }
+    }
+}
+
+/// The stickers of a raw message
+fn from_raw(raw: &Value) -> Vec<Sticker> {
+    let items = if raw["sticker_items"].is_array() {
+        &raw["sticker_items"]
+    } else {
+        &raw["stickers"]
+    };
+    items
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Sticker::from_value)
+        .collect()
+}
+
+/// Parse an array of raw messages, keeping the stickers serenity would drop
+pub fn parse_messages(raw: &Value) -> (Vec<Message>, Stickers) {
+    let mut msgs = Vec::new();
+    let mut stickers = Stickers::new();
+    for raw in raw.as_array().into_iter().flatten() {
+        let msg: Message = match serde_json::from_value(raw.clone()) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+        let msg_stickers = from_raw(raw);
+        if !msg_stickers.is_empty() {
+            stickers.insert(msg.id, msg_stickers);
+        }
+        msgs.push(msg);
+    }
+    (msgs, stickers)
+}
+
+/// A raw message with its stickers, which `parse_messages` reads back
+pub fn to_raw(msg: &Message, stickers: Option<&Vec<Sticker>>) -> Value {
+    let mut raw = serde_json::to_value(msg).unwrap_or(Value::Null);
+    if let (Some(stickers), Some(fields)) = (stickers, raw.as_object_mut()) {
+        let items = stickers.iter().map(Sticker::to_value).collect();
+        fields.insert("sticker_items".to_owned(), Value::Array(items));
+    }
+    raw
+}
+
+/// Fetch a page of messages of a channel, `query` holds the parameters, eg: `limit=50&before=1`
+pub fn fetch_messages(
+    ctx: &Context,
+    channel: ChannelId,
+    query: &str,
+) -> Result<(Vec<Message>, Stickers), reqwest::Error> {
+    let path = format!("/channels/{}/messages?{}", channel.0, query);
+    rest::request(ctx, Method::GET, &path, None).map(|raw| parse_messages(&raw))
+}
+
+/// Fetch the pinned messages of a channel, newest first
+pub fn fetch_pins(
+    ctx: &Context,
+    channel: ChannelId,
+) -> Result<(Vec<Message>, Stickers), reqwest::Error> {
+    let path = format!("/channels/{}/pins", channel.0);
+    rest::request(ctx, Method::GET, &path, None).map(|raw| parse_messages(&raw))
+}
+
+/// Messages consisting of only stickers look empty to serenity
+fn may_have_stickers(msg: &Message) -> bool {
+    match msg.kind {
+        MessageType::Regular => {
+            msg.content.is_empty() && msg.attachments.is_empty() && msg.embeds.is_empty()
+        },
+        _ => false,
+    }
+}
+
+/// Fetch the stickers of a new message that looks empty in the background and redraw it once they
+/// are known, messages of history pages come with their stickers
+pub fn fetch_if_needed(account: &str, buffer_name: &str, msg: &Message) {
+    if !may_have_stickers(msg) {
+        return;
+    }
+
+    let buffer_name = buffer_name.to_owned();
+    let (channel_id, message_id) = (msg.channel_id, msg.id);
+    let task_account = account.to_owned();
+    tasks::spawn_for_channel(account, channel_id, move || {
+        let account = task_account;
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let raw = match rest::request(
+            &ctx,
+            Method::GET,
+            &format!("/channels/{}/messages/{}", channel_id.0, message_id.0),
+            None,
+        ) {
+            Ok(raw) => raw,
+            Err(_) => return,
+        };
+        let stickers = from_raw(&raw);
+        if stickers.is_empty() {
+            return;
+        }
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                buffer.set_stickers(&ctx.cache, message_id, stickers);
+            }
+        });
+    });
+}
@@ -0,0 +1,273 @@
+//! Serenity does not know about threads, so they are tracked here from the raw gateway events
+use super::rest;
+use crate::{buffers, on_main, utils};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use reqwest::Method;
+use serde_json::Value;
+use serenity::{model::prelude::*, prelude::Context};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub id: ChannelId,
+    pub guild_id: GuildId,
+    pub parent_id: ChannelId,
+    pub name: String,
+    pub archived: bool,
+    pub joined: bool,
+    pub last_message_id: Option<MessageId>,
+}
+
+impl Thread {
+    fn from_value(value: &Value) -> Option<Thread> {
+        Some(Thread {
+            id: ChannelId(parse_id(&value["id"])?),
+            guild_id: GuildId(parse_id(&value["guild_id"])?),
+            parent_id: ChannelId(parse_id(&value["parent_id"])?),
+            name: value["name"].as_str()?.to_owned(),
+            archived: value["thread_metadata"]["archived"]
+                .as_bool()
+                .unwrap_or_default(),
+            joined: !value["member"].is_null(),
+            last_message_id: parse_id(&value["last_message_id"]).map(MessageId),
+        })
+    }
+}
+
+lazy_static! {
+    /// Known threads by account and id
+    static ref THREADS: Arc<Mutex<HashMap<(String, ChannelId), Thread>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+fn parse_id(value: &Value) -> Option<u64> {
+    value.as_str().and_then(|id| id.parse().ok())
+}
+
+/// Get a known thread by id
+pub fn get(account: &str, id: ChannelId) -> Option<Thread> {
+    THREADS.lock().get(&(account.to_owned(), id)).cloned()
+}
+
+/// All known threads of a channel, sorted by name
+pub fn threads_in(account: &str, parent: ChannelId) -> Vec<Thread> {
+    let mut threads: Vec<_> = THREADS
+        .lock()
+        .iter()
+        .filter(|((thread_account, _), t)| thread_account == account && t.parent_id == parent)
+        .map(|(_, t)| t.clone())
+        .collect();
+    threads.sort_by(|a, b| a.name.cmp(&b.name));
+    threads
+}
+
+/// Find a thread of a channel by its (weechat stripped) name or id
+pub fn search(account: &str, parent: ChannelId, name: &str) -> Option<Thread> {
+    threads_in(account, parent).into_iter().find(|t| {
+        parsing::weechat_arg_strip(&t.name).to_lowercase() == name.to_lowercase()
+            || t.id.0.to_string() == name
+    })
+}
+
+/// Forget the threads of a disconnected account
+pub fn clear(account: &str) {
+    THREADS
+        .lock()
+        .retain(|(thread_account, _), _| thread_account != account);
+}
+
+fn insert(account: &str, mut thread: Thread) -> (Thread, bool) {
+    let key = (account.to_owned(), thread.id);
+    let mut threads = THREADS.lock();
+    let is_new = if let Some(old) = threads.get(&key) {
+        // Update events do not include the membership
+        thread.joined |= old.joined;
+        false
+    } else {
+        true
+    };
+    threads.insert(key, thread.clone());
+    (thread, is_new)
+}
+
+fn set_joined(account: &str, id: ChannelId, joined: bool) -> Option<Thread> {
+    let mut threads = THREADS.lock();
+    let thread = threads.get_mut(&(account.to_owned(), id))?;
+    thread.joined = joined;
+    Some(thread.clone())
+}
+
+/// Update thread state from a gateway event serenity does not handle
+pub fn handle_event(ctx: &Context, account: &str, name: &str, raw: &Value) {
+    let current_user = ctx.cache.read().user.id;
+
+    match name {
+        "THREAD_CREATE" | "THREAD_UPDATE" => {
+            if let Some(thread) = Thread::from_value(raw) {
+                let (thread, is_new) = insert(account, thread);
+                if is_new && name == "THREAD_CREATE" {
+                    print_parent_status_message(
+                        account,
+                        &thread,
+                        &format!("New thread `{}` created", thread.name),
+                    );
+                }
+                sync_buffer(account, thread);
+            }
+        },
+        "THREAD_DELETE" => {
+            let id = match parse_id(&raw["id"]) {
+                Some(id) => ChannelId(id),
+                None => return,
+            };
+            let thread = THREADS.lock().remove(&(account.to_owned(), id));
+            let guild_id = match &thread {
+                Some(thread) => Some(thread.guild_id),
+                None => parse_id(&raw["guild_id"]).map(GuildId),
+            };
+            if let Some(thread) = thread {
+                print_parent_status_message(
+                    account,
+                    &thread,
+                    &format!("Thread `{}` deleted", thread.name),
+                );
+            }
+            // Its buffer is kept so the messages can still be read
+            buffers::mark_channel_deleted(account, guild_id, id);
+        },
+        "THREAD_LIST_SYNC" => {
+            let mut synced = Vec::new();
+            for thread in raw["threads"].as_array().into_iter().flatten() {
+                if let Some(thread) = Thread::from_value(thread) {
+                    synced.push(insert(account, thread).0.id);
+                }
+            }
+            for member in raw["members"].as_array().into_iter().flatten() {
+                if let Some(id) = parse_id(&member["id"]) {
+                    set_joined(account, ChannelId(id), true);
+                }
+            }
+            for id in synced {
+                if let Some(thread) = get(account, id) {
+                    sync_buffer(account, thread);
+                }
+            }
+        },
+        // Only sent for the current user
+        "THREAD_MEMBER_UPDATE" => {
+            if let Some(thread) =
+                parse_id(&raw["id"]).and_then(|id| set_joined(account, ChannelId(id), true))
+            {
+                sync_buffer(account, thread);
+            }
+        },
+        "THREAD_MEMBERS_UPDATE" => {
+            let id = match parse_id(&raw["id"]) {
+                Some(id) => ChannelId(id),
+                None => return,
+            };
+            let added = raw["added_members"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|m| parse_id(&m["user_id"]) == Some(current_user.0));
+            let removed = raw["removed_member_ids"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .any(|m| parse_id(m) == Some(current_user.0));
+
+            if added || removed {
+                if let Some(thread) = set_joined(account, id, added) {
+                    sync_buffer(account, thread);
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+/// Refresh the buffer of a thread, opening it if the thread has been joined and its parent
+/// channel has a buffer
+fn sync_buffer(account: &str, thread: Thread) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let ctx = &*ctx;
+
+        let buffer_name = utils::buffer_id_for_channel(&account, Some(thread.guild_id), thread.id);
+        let parent_name =
+            utils::buffer_id_for_channel(&account, Some(thread.guild_id), thread.parent_id);
+        let has_buffer = weecord.buffer_manager.get_buffer(&buffer_name).is_some();
+        let has_parent = weecord.buffer_manager.get_buffer(&parent_name).is_some();
+
+        if has_buffer || (thread.joined && !thread.archived && has_parent) {
+            buffers::create_buffer_from_thread(&ctx.cache, weecord, &account, &thread, false);
+        }
+    });
+}
+
+fn print_parent_status_message(account: &str, thread: &Thread, msg: &str) {
+    let buffer_name =
+        utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.parent_id);
+
+    let msg = msg.to_owned();
+    on_main(move |weechat| {
+        if let Some(buffer) = weechat.buffer_search("weecord", &buffer_name) {
+            let prefix = weechat.get_prefix("network").to_owned();
+            buffer.print(&(prefix + "\t" + msg.as_ref()));
+        }
+    })
+}
+
+/// Fetch the active threads of every guild, so joined threads get buffers without having to be
+/// listed first
+pub fn fetch_all_active(ctx: &Context, account: &str) {
+    let guilds: Vec<_> = ctx.cache.read().guilds.keys().cloned().collect();
+    for guild_id in guilds {
+        if let Err(e) = fetch_active(ctx, account, guild_id) {
+            crate::plugin_print(&format!("Unable to fetch active threads: {}", e));
+            return;
+        }
+    }
+}
+
+/// Fetch all active threads of a guild, including the current users memberships
+pub fn fetch_active(ctx: &Context, account: &str, guild_id: GuildId) -> Result<(), reqwest::Error> {
+    let response = rest::request(
+        ctx,
+        Method::GET,
+        &format!("/guilds/{}/threads/active", guild_id.0),
+        None,
+    )?;
+    handle_event(ctx, account, "THREAD_LIST_SYNC", &response);
+    Ok(())
+}
+
+/// Add the current user to a thread
+pub fn join(ctx: &Context, account: &str, thread: ChannelId) -> Result<(), reqwest::Error> {
+    rest::request(
+        ctx,
+        Method::PUT,
+        &format!("/channels/{}/thread-members/@me", thread.0),
+        None,
+    )?;
+    set_joined(account, thread, true);
+    Ok(())
+}
+
+/// Remove the current user from a thread
+pub fn leave(ctx: &Context, account: &str, thread: ChannelId) -> Result<(), reqwest::Error> {
+    rest::request(
+        ctx,
+        Method::DELETE,
+        &format!("/channels/{}/thread-members/@me", thread.0),
+        None,
+    )?;
+    set_joined(account, thread, false);
+    Ok(())
+}
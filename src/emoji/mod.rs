@@ -0,0 +1,217 @@
+//! Lookup of unicode emoji by their shortcode, using a bundled copy of the gemoji database
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+const EMOJI_DB: &str = include_str!("emoji.tsv");
+
+/// The variation selector requesting emoji presentation, which is often left out
+const VARIATION_SELECTOR: char = '\u{fe0f}';
+
+lazy_static! {
+    /// All shortcodes and their emoji, in database order
+    static ref EMOJIS: Vec<(&'static str, &'static str)> = EMOJI_DB
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '\t');
+            Some((parts.next()?, parts.next()?))
+        })
+        .collect();
+    static ref BY_SHORTCODE: HashMap<&'static str, &'static str> =
+        EMOJIS.iter().cloned().collect();
+    /// The first shortcode of every emoji, also keyed without variation selectors
+    static ref BY_EMOJI: HashMap<String, &'static str> = {
+        let mut by_emoji = HashMap::new();
+        for &(shortcode, emoji) in EMOJIS.iter() {
+            by_emoji.entry(emoji.to_owned()).or_insert(shortcode);
+            by_emoji
+                .entry(emoji.replace(VARIATION_SELECTOR, ""))
+                .or_insert(shortcode);
+        }
+        by_emoji
+    };
+    /// The length in chars of the longest emoji
+    static ref MAX_EMOJI_LEN: usize = BY_EMOJI
+        .keys()
+        .map(|emoji| emoji.chars().count())
+        .max()
+        .unwrap_or(1);
+}
+
+/// Find the emoji for a shortcode given without colons, eg: `thumbsup`
+pub fn from_shortcode(shortcode: &str) -> Option<&'static str> {
+    BY_SHORTCODE.get(shortcode).copied()
+}
+
+/// Find the shortcode of an emoji, without colons
+pub fn to_shortcode(emoji: &str) -> Option<&'static str> {
+    BY_EMOJI.get(emoji).copied()
+}
+
+/// All known shortcodes, without colons
+pub fn shortcodes() -> impl Iterator<Item = &'static str> {
+    EMOJIS.iter().map(|&(shortcode, _)| shortcode)
+}
+
+/// Replace `:shortcode:`s with their unicode emoji
+///
+/// Escaped shortcodes and custom emoji (`<:name:id>`) are left alone
+pub fn expand_shortcodes(input: &str) -> String {
+    lazy_static! {
+        static ref SHORTCODE: Regex = Regex::new(r"(\\|<a?)?:([\w+\-]+):(\d+>)?").unwrap();
+    }
+
+    SHORTCODE
+        .replace_all(input, |caps: &Captures| {
+            if caps.get(1).is_some() || caps.get(3).is_some() {
+                return caps[0].to_owned();
+            }
+            match from_shortcode(&caps[2]) {
+                Some(emoji) => emoji.to_owned(),
+                None => caps[0].to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+/// Emoji below U+1F000 that are shown as emoji by default, every other single codepoint emoji
+/// there is shown as text unless followed by a variation selector
+const EMOJI_PRESENTATION: &[(char, char)] = &[
+    ('\u{231a}', '\u{231b}'),
+    ('\u{23e9}', '\u{23ec}'),
+    ('\u{23f0}', '\u{23f0}'),
+    ('\u{23f3}', '\u{23f3}'),
+    ('\u{25fd}', '\u{25fe}'),
+    ('\u{2614}', '\u{2615}'),
+    ('\u{2648}', '\u{2653}'),
+    ('\u{267f}', '\u{267f}'),
+    ('\u{2693}', '\u{2693}'),
+    ('\u{26a1}', '\u{26a1}'),
+    ('\u{26aa}', '\u{26ab}'),
+    ('\u{26bd}', '\u{26be}'),
+    ('\u{26c4}', '\u{26c5}'),
+    ('\u{26ce}', '\u{26ce}'),
+    ('\u{26d4}', '\u{26d4}'),
+    ('\u{26ea}', '\u{26ea}'),
+    ('\u{26f2}', '\u{26f3}'),
+    ('\u{26f5}', '\u{26f5}'),
+    ('\u{26fa}', '\u{26fa}'),
+    ('\u{26fd}', '\u{26fd}'),
+    ('\u{2705}', '\u{2705}'),
+    ('\u{270a}', '\u{270b}'),
+    ('\u{2728}', '\u{2728}'),
+    ('\u{274c}', '\u{274c}'),
+    ('\u{274e}', '\u{274e}'),
+    ('\u{2753}', '\u{2755}'),
+    ('\u{2757}', '\u{2757}'),
+    ('\u{2795}', '\u{2797}'),
+    ('\u{27b0}', '\u{27b0}'),
+    ('\u{27bf}', '\u{27bf}'),
+    ('\u{2b1b}', '\u{2b1c}'),
+    ('\u{2b50}', '\u{2b50}'),
+    ('\u{2b55}', '\u{2b55}'),
+];
+
+/// Whether an emoji is a single symbol that is shown as text by default, eg: `©` or `™`
+fn has_text_presentation(emoji: &str) -> bool {
+    let mut chars = emoji.chars();
+    let symbol = match (chars.next(), chars.next()) {
+        (Some(symbol), None) => symbol,
+        _ => return false,
+    };
+    symbol < '\u{1f000}'
+        && !EMOJI_PRESENTATION
+            .iter()
+            .any(|&(start, end)| symbol >= start && symbol <= end)
+}
+
+/// Replace unicode emoji with their `:shortcode:`, for terminals that can't display emoji
+pub fn replace_with_shortcodes(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+
+    'outer: while let Some(first) = rest.chars().next() {
+        // Prefer the longest match, so sequences are not split into their parts
+        let boundaries: Vec<_> = rest
+            .char_indices()
+            .skip(1)
+            .map(|(i, _)| i)
+            .chain(std::iter::once(rest.len()))
+            .take(*MAX_EMOJI_LEN)
+            .collect();
+        for &end in boundaries.iter().rev() {
+            if let Some(shortcode) = to_shortcode(&rest[..end]) {
+                // Symbols like `©` are text unless they ask to be shown as emoji
+                if has_text_presentation(&rest[..end])
+                    && !rest[end..].starts_with(VARIATION_SELECTOR)
+                {
+                    break;
+                }
+                out.push(':');
+                out.push_str(shortcode);
+                out.push(':');
+                rest = &rest[end..];
+                // Drop a trailing variation selector that is not part of the database entry
+                rest = rest.trim_start_matches(VARIATION_SELECTOR);
+                continue 'outer;
+            }
+        }
+        out.push(first);
+        rest = &rest[first.len_utf8()..];
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_shortcodes() {
+        assert_eq!(expand_shortcodes("nice :+1:"), "nice 👍");
+        assert_eq!(expand_shortcodes(":smile::thumbsup:"), "😄👍");
+        assert_eq!(expand_shortcodes(":rainbow_flag:"), "🏳\u{fe0f}\u{200d}🌈");
+    }
+
+    #[test]
+    fn keeps_unknown_escaped_and_custom_shortcodes() {
+        assert_eq!(expand_shortcodes(":not_an_emoji:"), ":not_an_emoji:");
+        assert_eq!(expand_shortcodes("\\:smile:"), "\\:smile:");
+        assert_eq!(expand_shortcodes("<:smile:123>"), "<:smile:123>");
+        assert_eq!(expand_shortcodes("<a:smile:123>"), "<a:smile:123>");
+        assert_eq!(expand_shortcodes("10:30:45"), "10:30:45");
+    }
+
+    #[test]
+    fn replaces_emoji_with_their_first_shortcode() {
+        assert_eq!(replace_with_shortcodes("nice 👍"), "nice :+1:");
+        assert_eq!(replace_with_shortcodes("😄👍"), ":smile::+1:");
+    }
+
+    #[test]
+    fn sequences_are_not_split() {
+        assert_eq!(
+            replace_with_shortcodes("👩\u{200d}💻"),
+            ":woman_technologist:"
+        );
+        assert_eq!(
+            replace_with_shortcodes("🏳\u{fe0f}\u{200d}🌈"),
+            ":rainbow_flag:"
+        );
+        assert_eq!(replace_with_shortcodes("🇺🇸"), ":us:");
+    }
+
+    #[test]
+    fn variation_selectors_are_optional() {
+        assert_eq!(replace_with_shortcodes("❤\u{fe0f}"), ":heart:");
+        assert_eq!(replace_with_shortcodes("🏳\u{fe0f}"), ":white_flag:");
+    }
+
+    #[test]
+    fn text_presentation_symbols_stay_text() {
+        assert_eq!(replace_with_shortcodes("© 2021 ACME™ ®"), "© 2021 ACME™ ®");
+        assert_eq!(replace_with_shortcodes("©\u{fe0f}"), ":copyright:");
+        assert_eq!(replace_with_shortcodes("⚡"), ":zap:");
+    }
+}
@@ -1,30 +1,42 @@
 use crate::{
     buffers::load_pin_buffer_history,
     command::Args,
-    discord, on_main, plugin_print, utils,
+    discord, on_main, plugin_print, tasks, utils,
     utils::{BufferExt, ChannelExt},
+    weechat_utils::{MessageManager, PendingMessage},
 };
-use crossbeam_channel::unbounded;
 use lazy_static::lazy_static;
-use serenity::{
-    client::bridge::gateway,
-    model::{channel::ReactionType, prelude::*},
-    prelude::*,
-};
+use serenity::{client::bridge::gateway, model::prelude::*, prelude::*};
 use std::{
+    collections::{HashMap, HashSet},
     iter::FromIterator,
-    sync::Arc,
-    thread,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use weechat::{Buffer, CompletionPosition, ConfigOption, ReturnCode, Weechat};
 
 lazy_static! {
-    static ref LAST_TYPING_TIMESTAMP: Arc<Mutex<u64>> = Arc::new(Mutex::new(0));
+    /// When each account last told Discord it is typing
+    static ref LAST_TYPING_TIMESTAMP: Arc<Mutex<HashMap<String, u64>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    /// The channels of each guild we subscribed to typing and activity events for
+    static ref GUILD_SUBSCRIPTIONS: Arc<Mutex<HashMap<(String, GuildId), HashSet<ChannelId>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Forget the subscriptions of an account, a new gateway session starts without any
+pub(crate) fn clear_guild_subscriptions(account: &str) {
+    GUILD_SUBSCRIPTIONS
+        .lock()
+        .retain(|(sub_account, _), _| sub_account != account);
 }
 
 pub struct HookHandles {
     _buffer_switch_handle: weechat::SignalHook<()>,
+    _buffer_closing_handle: weechat::SignalHook<()>,
     _buffer_typing_handle: weechat::SignalHook<()>,
     _command_handles: Vec<weechat::CommandHook<()>>,
     _query_handle: weechat::CommandRunHook<()>,
@@ -35,6 +47,9 @@ pub struct HookHandles {
     _dm_completion_handle: weechat::CompletionHook<()>,
     _nick_completion_handle: weechat::CompletionHook<()>,
     _role_completion_handle: weechat::CompletionHook<()>,
+    _thread_completion_handle: weechat::CompletionHook<()>,
+    _emoji_completion_handle: weechat::CompletionHook<()>,
+    _account_completion_handle: weechat::CompletionHook<()>,
 }
 
 pub fn init(weechat: &Weechat) -> HookHandles {
@@ -46,6 +61,12 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _buffer_closing_handle = weechat.hook_signal(
+        "buffer_closing",
+        |_, _, value| handle_buffer_closing(value),
+        None,
+    );
+
     let _buffer_typing_handle = weechat.hook_signal(
         "input_text_changed",
         |_, weechat, value| handle_buffer_typing(weechat, value),
@@ -59,9 +80,10 @@ pub fn init(weechat: &Weechat) -> HookHandles {
                 return ReturnCode::Error;
             };
 
-            handle_query(&Args::from_cmd(
-                &command.replace("/query ", "/discord query "),
-            ))
+            handle_query(
+                &buffer.account(),
+                &Args::from_cmd(&command.replace("/query ", "/discord query ")),
+            )
         },
         None,
     );
@@ -113,8 +135,30 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _thread_completion_handle = weechat.hook_completion(
+        "weecord_thread_completion",
+        "Completion for the threads of a Discord channel",
+        |_, ref buffer, _, completions| handle_thread_completion(buffer, completions),
+        None,
+    );
+
+    let _emoji_completion_handle = weechat.hook_completion(
+        "weecord_emoji",
+        "Completion for standard and guild emoji shortcodes",
+        |_, ref buffer, _, completions| handle_emoji_completion(buffer, completions),
+        None,
+    );
+
+    let _account_completion_handle = weechat.hook_completion(
+        "weecord_account_completion",
+        "Completion for weecord accounts",
+        |_, ref buffer, _, completions| handle_account_completion(buffer, completions),
+        None,
+    );
+
     HookHandles {
         _buffer_switch_handle,
+        _buffer_closing_handle,
         _buffer_typing_handle,
         _command_handles,
         _query_handle,
@@ -125,6 +169,9 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         _dm_completion_handle,
         _nick_completion_handle,
         _role_completion_handle,
+        _thread_completion_handle,
+        _emoji_completion_handle,
+        _account_completion_handle,
     }
 }
 
@@ -138,10 +185,12 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
     let guild = buffer.guild_id();
 
     if let Some(channel) = channel {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let ctx = &*ctx;
+        discord::read_state::ack_shown(&buffer.account(), channel);
 
         if let Some(edit) = parsing::parse_line_edit(text) {
             let weechat = buffer.get_weechat();
@@ -187,39 +236,187 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
             return;
         }
         if let Some(reaction) = parsing::parse_reaction(text) {
+            let reaction_type =
+                match utils::resolve_reaction_emoji(&ctx.cache, guild, &reaction.emoji) {
+                    Some(reaction_type) => reaction_type,
+                    None => {
+                        let weechat = buffer.get_weechat();
+                        buffer.print(&format!(
+                            "{}\tUnknown emoji in reaction: {}",
+                            weechat.get_prefix("network"),
+                            text
+                        ));
+                        return;
+                    },
+                };
             if let Ok(msgs) =
                 channel.messages(ctx, |retriever| retriever.limit(reaction.line as u64))
             {
                 for (i, msg) in msgs.iter().enumerate() {
                     if i + 1 == reaction.line {
                         if reaction.add {
-                            let _ =
-                                msg.react(ctx, ReactionType::Unicode(reaction.unicode.to_string()));
+                            let _ = msg.react(ctx, reaction_type.clone());
                         } else {
-                            let _ = channel.delete_reaction(
-                                &ctx,
-                                msg.id,
-                                None,
-                                ReactionType::Unicode(reaction.unicode.to_string()),
-                            );
+                            let _ =
+                                channel.delete_reaction(&ctx, msg.id, None, reaction_type.clone());
                         }
                     }
                 }
             }
             return;
         }
-        let text = utils::create_mentions(&ctx.cache, guild, text);
-        let text = utils::expand_guild_emojis(&ctx.cache, guild, &text);
-        if let Err(e) = channel.say(ctx, text) {
-            let weechat = buffer.get_weechat();
+        let weechat = buffer.get_weechat();
+        let weecord = crate::upgrade_plugin(&weechat);
+        let buffer = match weecord.buffer_manager.get_buffer(&buffer.get_name()) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        if let Some(reply) = parsing::parse_reply(text) {
+            send_reply(&buffer, reply.line, reply.text);
+            return;
+        }
+        send_message(&buffer, new_pending(ctx, guild, text, None));
+    }
+}
+
+/// A message to be sent by the current user, with mentions and emoji expanded
+fn new_pending(
+    ctx: &Context,
+    guild: Option<GuildId>,
+    text: &str,
+    reply_to: Option<MessageId>,
+) -> PendingMessage {
+    let content = utils::create_mentions(&ctx.cache, guild, text);
+    let content = utils::expand_guild_emojis(&ctx.cache, guild, &content);
+    let content = crate::emoji::expand_shortcodes(&content);
+
+    let author = {
+        let cache = ctx.cache.read();
+        guild
+            .and_then(|guild| cache.member(guild, cache.user.id))
+            .map(|member| member.display_name().to_string())
+            .unwrap_or_else(|| cache.user.name.clone())
+    };
+    PendingMessage {
+        nonce: new_nonce(),
+        author,
+        text: text.to_owned(),
+        content,
+        reply_to,
+        failed: false,
+    }
+}
+
+/// A nonce like the ones the official client sends, a snowflake of the current time
+fn new_nonce() -> String {
+    const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+    static INCREMENT: AtomicU64 = AtomicU64::new(0);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64;
+    let increment = INCREMENT.fetch_add(1, Ordering::Relaxed) & 0xfff;
+    (((timestamp - DISCORD_EPOCH) << 22) | increment).to_string()
+}
+
+/// Print a message right away and send it in the background, the pending message is replaced
+/// when Discord echoes it back or marked as failed if it can't be sent
+pub fn send_message(buffer: &MessageManager, pending: PendingMessage) {
+    buffer.add_pending(pending.clone());
+    send_pending(buffer, pending);
+}
+
+fn send_pending(buffer: &MessageManager, pending: PendingMessage) {
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => return,
+    };
+    let guild = buffer.guild_id();
+    let account = buffer.account();
+    let buffer_name = buffer.get_name().into_owned();
+    // Messages of a channel are sent one after another so they arrive in order
+    tasks::spawn_send(&buffer.account(), channel, move || {
+        let result = match discord::get_ctx(&account) {
+            Some(ctx) => channel
+                .send_message(&*ctx, |m| {
+                    m.content(&pending.content);
+                    m.0.insert("nonce", pending.nonce.clone().into());
+                    if let Some(reply_to) = pending.reply_to {
+                        let mut reference = serde_json::json!({
+                            "message_id": reply_to.0.to_string(),
+                            "channel_id": channel.0.to_string(),
+                        });
+                        if let Some(guild) = guild {
+                            reference["guild_id"] = guild.0.to_string().into();
+                        }
+                        m.0.insert("message_reference", reference);
+                    }
+                    m
+                })
+                .map_err(|e| e.to_string()),
+            None => Err(format!("{} is not connected", account)),
+        };
+
+        on_main(move |weecord| {
+            let buffer = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            match result {
+                // The echo over the gateway may never arrive, eg: if the connection drops
+                Ok(msg) => {
+                    if let Some(ctx) = discord::get_ctx(&account) {
+                        buffer.confirm_pending(&ctx.cache, &pending.nonce, &msg);
+                    }
+                },
+                Err(e) => {
+                    buffer.set_pending_failed(&pending.nonce, true);
+                    buffer.print(&format!(
+                        "{}\tUnable to send message: {}",
+                        weecord.get_prefix("network"),
+                        e
+                    ));
+                },
+            }
+        });
+    });
+}
+
+/// Send the messages of a buffer that failed to send again, returns how many there were
+pub fn retry_failed(buffer: &MessageManager) -> usize {
+    let failed = buffer.failed_messages();
+    for pending in &failed {
+        buffer.set_pending_failed(&pending.nonce, false);
+    }
+    let count = failed.len();
+    for pending in failed {
+        send_pending(buffer, pending);
+    }
+    count
+}
+
+/// Send a message to the buffers channel as a reply to the nth most recent message in the buffer
+pub fn send_reply(buffer: &MessageManager, line: usize, text: &str) {
+    let weechat = buffer.get_weechat();
+    let target = match buffer.messages().iter().rev().nth(line - 1) {
+        Some(target) => target.id,
+        None => {
             buffer.print(&format!(
-                "{}\tUnable to send message to {}: {:#?}",
+                "{}\tMessage {} is not loaded",
                 weechat.get_prefix("network"),
-                channel.0,
-                e
+                line
             ));
-        }
-    }
+            return;
+        },
+    };
+
+    let ctx = match crate::discord::get_ctx(&buffer.account()) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let pending = new_pending(&ctx, buffer.guild_id(), text, Some(target));
+    send_message(buffer, pending);
 }
 
 fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
@@ -227,6 +424,9 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
         let buffer = unsafe { crate::utils::buffer_from_ptr(buffer_ptr) };
         let weechat = buffer.get_weechat();
         let weecord = crate::upgrade_plugin(&weechat);
+        let cache_size = weecord.config.message_cache_size.value() as usize;
+        weecord.buffer_manager.save_message_cache(cache_size);
+
         let buffer = match weecord
             .buffer_manager
             .get_buffer(buffer.get_name().as_ref())
@@ -235,9 +435,17 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
             None => return ReturnCode::Ok,
         };
 
-        // Wait until messages have been loaded to acknowledge them
-        let (tx, rx) = unbounded();
-        if !buffer.history_loaded() {
+        let channel_id = buffer.channel_id();
+        let account = buffer.account();
+        // Messages are acknowledged once they have been loaded
+        let on_loaded = move || {
+            if let Some(channel_id) = channel_id {
+                subscribe_and_ack(&account, channel_id);
+            }
+        };
+        if buffer.history_loaded() {
+            on_loaded();
+        } else {
             let pinned_channel_id = utils::pins_for_channel(&buffer);
 
             if pinned_channel_id.is_some() {
@@ -247,71 +455,85 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
 
             let fetch_count = weecord.config.message_fetch_count.value();
 
-            crate::buffers::load_history(&buffer, tx, fetch_count);
+            crate::buffers::load_history(&buffer, on_loaded, fetch_count, cache_size > 0);
         }
 
         if !buffer.nicks_loaded() {
             crate::buffers::load_nicks(&buffer);
         }
+    }
+    ReturnCode::Ok
+}
 
-        let channel_id = buffer.channel_id();
-
-        thread::spawn(move || {
-            if rx.recv().is_err() {
-                return;
-            }
-            let ctx = match discord::get_ctx() {
-                Some(s) => s,
-                None => return,
-            };
-            if let Some(channel) = channel_id.and_then(|id| id.to_channel_cached(&ctx)) {
-                if let Some(guild_channel) = channel.clone().guild() {
-                    let guild_id = guild_channel.read().guild_id;
-                    use std::collections::{HashMap, HashSet};
-                    lazy_static! {
-                        static ref CHANNELS: Arc<Mutex<HashMap<GuildId, HashSet<ChannelId>>>> =
-                            Arc::new(Mutex::new(HashMap::new()));
-                    }
-
-                    let mut channels = CHANNELS.lock();
-                    let send = if let Some(guild_channels) = channels.get_mut(&guild_id) {
-                        guild_channels.insert(channel.id())
-                    } else {
+/// Subscribe to typing and activity events of a guild channel and acknowledge its messages
+fn subscribe_and_ack(account: &str, channel_id: ChannelId) {
+    let task_account = account.to_owned();
+    tasks::spawn_for_channel(account, channel_id, move || {
+        let account = task_account;
+        let ctx = match discord::get_ctx(&account) {
+            Some(s) => s,
+            None => return,
+        };
+        let ctx = &*ctx;
+        if let Some(channel) = channel_id.to_channel_cached(&ctx) {
+            if let Some(guild_channel) = channel.clone().guild() {
+                let guild_id = guild_channel.read().guild_id;
+
+                let key = (account.clone(), guild_id);
+                let mut channels = GUILD_SUBSCRIPTIONS.lock();
+                let send = if let Some(guild_channels) = channels.get_mut(&key) {
+                    guild_channels.insert(channel.id())
+                } else {
+                    channels.insert(
+                        key.clone(),
+                        HashSet::from_iter(vec![channel.id()].into_iter()),
+                    );
+                    true
+                };
+                if send {
+                    let channels = channels.get(&key).unwrap();
+                    let channels_obj: HashMap<String, Vec<Vec<_>>> = HashMap::from_iter(
                         channels
-                            .insert(guild_id, HashSet::from_iter(vec![channel.id()].into_iter()));
-                        true
+                            .iter()
+                            .map(|ch| (format!("{}", ch.0), vec![vec![0, 99]])),
+                    );
+
+                    let msg = json::object! {
+                        "op" => 14,
+                        "d" => json::object! {
+                            "guild_id" => format!("{}", guild_id.0),
+                            "typing" => true,
+                            "activities" => true,
+                            "channels" => channels_obj,
+                        }
                     };
-                    if send {
-                        let channels = channels.get(&guild_id).unwrap();
-                        let channels_obj: HashMap<String, Vec<Vec<_>>> = HashMap::from_iter(
-                            channels
-                                .iter()
-                                .map(|ch| (format!("{}", ch.0), vec![vec![0, 99]])),
-                        );
-
-                        let msg = json::object! {
-                            "op" => 14,
-                            "d" => json::object! {
-                                "guild_id" => format!("{}", guild_id.0),
-                                "typing" => true,
-                                "activities" => true,
-                                "channels" => channels_obj,
-                            }
-                        };
-                        ctx.shard
-                            .websocket_message(gateway::Message::Text(msg.to_string()));
-                    }
+                    ctx.shard
+                        .websocket_message(gateway::Message::Text(msg.to_string()));
                 }
+            }
 
-                if let Some(rs) = ctx.cache.read().read_state.get(&channel.id()) {
-                    if let Some(last_message_id) = channel.last_message() {
-                        if rs.last_message_id != last_message_id {
-                            let _ = channel.id().ack_message(&ctx, last_message_id);
-                        }
-                    }
+            if let Some(last_message_id) = channel.last_message() {
+                let unread = ctx
+                    .cache
+                    .read()
+                    .read_state
+                    .get(&channel.id())
+                    .map_or(false, |rs| rs.last_message_id != last_message_id);
+                if unread {
+                    let _ = discord::read_state::ack(ctx, &account, channel.id(), last_message_id);
                 }
             }
-        });
+        }
+    });
+}
+
+/// Drop the background work still queued for the channel of a closed buffer
+fn handle_buffer_closing(data: weechat::SignalHookValue) -> ReturnCode {
+    if let weechat::SignalHookValue::Pointer(buffer_ptr) = data {
+        let buffer = unsafe { crate::utils::buffer_from_ptr(buffer_ptr) };
+        if let Some(channel_id) = buffer.channel_id() {
+            tasks::cancel_channel(&buffer.account(), channel_id);
+        }
     }
     ReturnCode::Ok
 }
@@ -335,14 +557,18 @@ fn handle_buffer_typing(weechat: &Weechat, data: weechat::SignalHookValue) -> Re
                     .expect("Time went backwards")
                     .as_secs() as u64;
 
-                if *LAST_TYPING_TIMESTAMP.lock() + 9 < timestamp_now {
-                    *LAST_TYPING_TIMESTAMP.lock() = timestamp_now;
+                let account = buffer.account();
+                let mut last_typing = LAST_TYPING_TIMESTAMP.lock();
+                let last_typing = last_typing.entry(account.clone()).or_default();
+                if *last_typing + 9 < timestamp_now {
+                    *last_typing = timestamp_now;
 
-                    std::thread::spawn(move || {
-                        let ctx = match discord::get_ctx() {
+                    tasks::spawn(move || {
+                        let ctx = match discord::get_ctx(&account) {
                             Some(s) => s,
                             None => return,
                         };
+                        let ctx = &*ctx;
                         let _ = channel_id.broadcast_typing(&ctx.http);
                     });
                 }
@@ -369,10 +595,11 @@ fn handle_channel_completion(buffer: &Buffer, completion: weechat::Completion) -
     };
 
     // Match mangled name to the real name
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
+    let ctx = &*ctx;
 
     for guild in ctx.cache.read().guilds.values() {
         let guild = guild.read();
@@ -397,11 +624,12 @@ fn handle_channel_completion(buffer: &Buffer, completion: weechat::Completion) -
     ReturnCode::Ok
 }
 
-fn handle_guild_completion(_buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+fn handle_guild_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
+    let ctx = &*ctx;
     for guild in ctx.cache.read().guilds.values() {
         let name = parsing::weechat_arg_strip(&guild.read().name);
         completion.add(&name);
@@ -409,11 +637,12 @@ fn handle_guild_completion(_buffer: &Buffer, completion: weechat::Completion) ->
     ReturnCode::Ok
 }
 
-fn handle_dm_completion(_buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+fn handle_dm_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
+    let ctx = &*ctx;
     for dm in ctx.cache.read().private_channels.values() {
         completion.add(&dm.read().recipient.read().name);
     }
@@ -421,10 +650,11 @@ fn handle_dm_completion(_buffer: &Buffer, completion: weechat::Completion) -> Re
 }
 
 fn handle_nick_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
+    let ctx = &*ctx;
 
     let channel_id = buffer.channel_id();
 
@@ -446,10 +676,11 @@ fn handle_nick_completion(buffer: &Buffer, completion: weechat::Completion) -> R
 }
 
 fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
+    let ctx = &*ctx;
 
     let guild = buffer.guild_id();
 
@@ -465,10 +696,47 @@ fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> R
     ReturnCode::Ok
 }
 
+fn handle_thread_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    if let Some(parent) = buffer.parent_channel_id().or_else(|| buffer.channel_id()) {
+        for thread in discord::threads::threads_in(&buffer.account(), parent) {
+            completion.add(&parsing::weechat_arg_strip(&thread.name));
+        }
+    }
+
+    ReturnCode::Ok
+}
+
+fn handle_emoji_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    if let Some(ctx) = discord::get_ctx(&buffer.account()) {
+        if let Some(guild) = buffer
+            .guild_id()
+            .and_then(|id| id.to_guild_cached(&ctx.cache))
+        {
+            for emoji in guild.read().emojis.values() {
+                completion.add(&format!(":{}:", emoji.name));
+            }
+        }
+    }
+
+    for shortcode in crate::emoji::shortcodes() {
+        completion.add(&format!(":{}:", shortcode));
+    }
+
+    ReturnCode::Ok
+}
+
+fn handle_account_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let weechat = buffer.get_weechat();
+    for account in crate::upgrade_plugin(&weechat).config.account_names() {
+        completion.add(&account);
+    }
+    ReturnCode::Ok
+}
+
 // TODO: Make this faster
-// TODO: Handle command options
-pub fn handle_query(args: &Args) -> ReturnCode {
+pub fn handle_query(account: &str, args: &Args) -> ReturnCode {
     let mut owned_args = args.clone();
+    let mut account = account.to_owned();
 
     let mut noswitch = false;
     if let Some(&arg) = owned_args.args.front() {
@@ -477,10 +745,17 @@ pub fn handle_query(args: &Args) -> ReturnCode {
             owned_args.args.pop_front();
         }
     }
+    // Like the irc plugin, the server option picks the account to query from
     if let Some(&arg) = owned_args.args.front() {
         if arg == "-server" {
-            plugin_print("The server option is not yet supported");
-            return ReturnCode::Error;
+            owned_args.args.pop_front();
+            account = match owned_args.args.pop_front() {
+                Some(server) => server.to_owned(),
+                None => {
+                    plugin_print("query requires an account name after -server");
+                    return ReturnCode::Error;
+                },
+            };
         }
     }
 
@@ -494,11 +769,15 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         None => return ReturnCode::Ok,
     };
 
-    thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+    tasks::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
-            _ => return,
+            _ => {
+                plugin_print(&format!("{} is not connected", account));
+                return;
+            },
         };
+        let ctx = &*ctx;
         let current_user = &ctx.cache.read().user;
 
         let mut found_members: Vec<User> = Vec::new();
@@ -531,13 +810,15 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         if let Some(target) = found_members.get(0) {
             if let Ok(chan) = target.create_dm_channel(ctx) {
                 on_main(move |weecord| {
-                    let ctx = match crate::discord::get_ctx() {
+                    let ctx = match crate::discord::get_ctx(&account) {
                         Some(ctx) => ctx,
                         _ => return,
                     };
+                    let ctx = &*ctx;
                     crate::buffers::create_buffer_from_dm(
                         &ctx.cache,
                         &weecord,
+                        &account,
                         Channel::Private(Arc::new(RwLock::new(chan))),
                         &current_user_name,
                         !noswitch,
@@ -560,13 +841,15 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
         return ReturnCode::Ok;
     };
 
+    let account = buffer.account();
     let guilds;
     let mut substr;
     {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return ReturnCode::Error,
         };
+        let ctx = &*ctx;
         substr = command["/nick".len()..].trim().to_owned();
         let mut split = substr.split(' ');
         let all = split.next() == Some("-all");
@@ -588,27 +871,24 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
         };
     }
 
-    thread::spawn(move || {
-        {
-            let ctx = match crate::discord::get_ctx() {
+    // Make it less spammy by changing one nick per second
+    for (i, guild) in guilds.into_iter().enumerate() {
+        let account = account.clone();
+        let substr = substr.clone();
+        tasks::spawn_after(Duration::from_secs(i as u64), move || {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
-            let should_sleep = guilds.len() > 1;
-            for guild in guilds {
-                let new_nick = if substr.is_empty() {
-                    None
-                } else {
-                    Some(substr.as_str())
-                };
-                let _ = guild.edit_nickname(ctx, new_nick);
-                // Make it less spammy
-                if should_sleep {
-                    thread::sleep(Duration::from_secs(1));
-                }
-            }
-        }
-    });
+            let ctx = &*ctx;
+            let new_nick = if substr.is_empty() {
+                None
+            } else {
+                Some(substr.as_str())
+            };
+            let _ = guild.edit_nickname(ctx, new_nick);
+        });
+    }
     ReturnCode::OkEat
 }
 
@@ -617,6 +897,7 @@ fn handle_join(buffer: &Buffer, command: &str) -> ReturnCode {
 
     crate::command::join(
         &buffer.get_weechat(),
+        &buffer.account(),
         &crate::command::Args::from_cmd(&format!("/discord {}", &command[1..])),
         verbose,
     )
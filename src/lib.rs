@@ -5,8 +5,11 @@ mod buffers;
 mod command;
 mod config;
 mod discord;
+mod emoji;
 mod hook;
+mod message_cache;
 mod sync;
+mod tasks;
 mod utils;
 mod weechat_utils;
 
@@ -36,8 +39,6 @@ impl WeechatPlugin for Discord {
         let config = config::init(&weechat);
         let buffer_manager = buffers::init(&weechat);
 
-        let autostart = config.autostart.value();
-
         let weecord = Discord {
             weechat,
             config,
@@ -47,8 +48,16 @@ impl WeechatPlugin for Discord {
             _bar_handles,
         };
 
-        if !args.contains(&"-a".to_owned()) && autostart {
-            weecord.connect();
+        if !args.contains(&"-a".to_owned()) {
+            for account in weecord.config.account_names() {
+                let autostart = weecord
+                    .config
+                    .with_account(&account, |options| options.autostart.value())
+                    .unwrap_or_default();
+                if autostart {
+                    weecord.connect(&account);
+                }
+            }
         }
 
         Ok(weecord)
@@ -56,13 +65,26 @@ impl WeechatPlugin for Discord {
 }
 
 impl Discord {
-    fn connect(&self) {
-        if crate::discord::DISCORD.lock().is_some() {
-            plugin_print("Already connected");
+    fn connect(&self, account: &str) {
+        if crate::discord::is_connected(account) {
+            plugin_print(&format!("{} is already connected", account));
             return;
         }
 
-        let token = self.config.token.value().into_owned();
+        let options = self.config.with_account(account, |options| {
+            (
+                options.token.value().into_owned(),
+                options.irc_mode.value(),
+                options.option_name("token"),
+            )
+        });
+        let (token, irc_mode, token_option) = match options {
+            Some(options) => options,
+            None => {
+                self.print(&format!("Error: unknown account {}", account));
+                return;
+            },
+        };
 
         let token = if token.starts_with("${sec.data") {
             self.eval_string_expression(&token).map(Cow::into_owned)
@@ -71,10 +93,20 @@ impl Discord {
         };
         if let Some(t) = token {
             if !t.is_empty() {
-                discord::init(&self, &t, self.config.irc_mode.value());
+                discord::init(&self, account, &t, irc_mode);
             } else {
-                self.print("Error: weecord.main.token is not set. To set it, run:");
-                self.print("/discord token 123456789ABCDEF");
+                self.print(&format!(
+                    "Error: {} is not set. To set it, run:",
+                    token_option
+                ));
+                if account == config::MAIN_ACCOUNT {
+                    self.print("/discord token 123456789ABCDEF");
+                } else {
+                    self.print(&format!(
+                        "/discord token -account {} 123456789ABCDEF",
+                        account
+                    ));
+                }
             }
         } else {
             self.print("Error: failed to evaluate token option, expected valid ${sec.data...}");
@@ -84,8 +116,10 @@ impl Discord {
 
 impl Drop for Discord {
     fn drop(&mut self) {
+        self.buffer_manager
+            .flush_message_cache(self.config.message_cache_size.value() as usize);
         // TODO: Why is the config file not saved on quit?
-        self.config.config.write()
+        self.config.write()
     }
 }
 
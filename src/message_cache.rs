@@ -0,0 +1,247 @@
+//! On disk storage of the most recent messages of each channel, so buffers can be drawn before
+//! their history has been fetched
+//!
+//! Each account has its own directory, messages hold state of the account that fetched them (eg:
+//! whether it reacted)
+use crate::{
+    discord::stickers::{self, Stickers},
+    tasks,
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde_json::Value;
+use serenity::model::{channel::Message, id::ChannelId};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+use weechat::Weechat;
+
+lazy_static! {
+    /// The newest messages of each cache file that are waiting to be written
+    static ref UNWRITTEN: Mutex<HashMap<PathBuf, (Vec<Message>, Stickers)>> =
+        Mutex::new(HashMap::new());
+    /// Held while writing, so an older version of a cache can't be written after a newer one
+    static ref WRITING: Mutex<()> = Mutex::new(());
+}
+
+fn cache_dir(weechat: &Weechat) -> Option<PathBuf> {
+    let weechat_dir = weechat
+        .info_get("weechat_data_dir", "")
+        .or_else(|| weechat.info_get("weechat_dir", ""))?;
+    Some(
+        PathBuf::from(weechat_dir.as_ref())
+            .join("weecord")
+            .join("message_cache"),
+    )
+}
+
+fn account_dir(weechat: &Weechat, account: &str) -> Option<PathBuf> {
+    cache_dir(weechat).map(|dir| dir.join(account))
+}
+
+fn cache_file(weechat: &Weechat, account: &str, channel: ChannelId) -> Option<PathBuf> {
+    account_dir(weechat, account).map(|dir| dir.join(format!("{}.json", channel.0)))
+}
+
+/// The cache files of every account, along with any left over from before caches were split by
+/// account
+fn cache_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let path = entry?.path();
+                if is_cache_file(&path) {
+                    files.push(path);
+                }
+            }
+        } else if is_cache_file(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn is_cache_file(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+/// Load the cached messages of a channel, oldest first, along with their stickers
+///
+/// A missing or unreadable cache is treated as empty
+pub fn load(weechat: &Weechat, account: &str, channel: ChannelId) -> (Vec<Message>, Stickers) {
+    cache_file(weechat, account, channel)
+        .map(|path| read(&path))
+        .unwrap_or_default()
+}
+
+fn read(path: &Path) -> (Vec<Message>, Stickers) {
+    fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice::<Value>(&data).ok())
+        .map(|raw| stickers::parse_messages(&raw))
+        .unwrap_or_default()
+}
+
+/// Store the newest `max` messages of a channel in the background
+pub fn store_later(
+    weechat: &Weechat,
+    account: &str,
+    channel: ChannelId,
+    mut messages: Vec<Message>,
+    stickers: Stickers,
+    max: usize,
+) {
+    let path = match cache_file(weechat, account, channel) {
+        Some(path) => path,
+        None => return,
+    };
+    let messages = messages.split_off(messages.len().saturating_sub(max));
+    UNWRITTEN.lock().insert(path.clone(), (messages, stickers));
+
+    tasks::spawn(move || {
+        let _writing = WRITING.lock();
+        // Already written by a task spawned later
+        let (messages, stickers) = match UNWRITTEN.lock().remove(&path) {
+            Some(unwritten) => unwritten,
+            None => return,
+        };
+        if let Err(e) = write(&path, &messages, &stickers, max) {
+            crate::plugin_print(&format!("Unable to write message cache: {}", e));
+        }
+    });
+}
+
+/// Write the caches that are waiting to be written right away, eg: before the plugin is unloaded
+pub fn flush() -> io::Result<()> {
+    let _writing = WRITING.lock();
+    let unwritten: Vec<_> = UNWRITTEN.lock().drain().collect();
+    for (path, (messages, stickers)) in unwritten {
+        write(&path, &messages, &stickers, messages.len())?;
+    }
+    Ok(())
+}
+
+fn write(path: &Path, messages: &[Message], stickers: &Stickers, max: usize) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let messages: Vec<_> = messages[messages.len().saturating_sub(max)..]
+        .iter()
+        .map(|msg| stickers::to_raw(msg, stickers.get(&msg.id)))
+        .collect();
+    let data = serde_json::to_vec(&messages)?;
+
+    // Write then rename so a crash can't leave a truncated cache behind
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(tmp_path, path)
+}
+
+/// Remove the cache of every channel, returning the amount of channels removed
+pub fn clear(weechat: &Weechat) -> io::Result<usize> {
+    let dir = match cache_dir(weechat) {
+        Some(dir) if dir.exists() => dir,
+        _ => return Ok(0),
+    };
+
+    let _writing = WRITING.lock();
+    UNWRITTEN.lock().clear();
+    let files = cache_files(&dir)?;
+    for path in &files {
+        fs::remove_file(path)?;
+    }
+    Ok(files.len())
+}
+
+/// The amount of cached channels and their total size in bytes
+pub fn stats(weechat: &Weechat) -> io::Result<(usize, u64)> {
+    let dir = match cache_dir(weechat) {
+        Some(dir) if dir.exists() => dir,
+        _ => return Ok((0, 0)),
+    };
+
+    let files = cache_files(&dir)?;
+    let mut size = 0;
+    for path in &files {
+        size += fs::metadata(path)?.len();
+    }
+    Ok((files.len(), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use serenity::model::id::MessageId;
+
+    fn message(id: u64) -> Message {
+        serde_json::from_value(json!({
+            "id": id.to_string(),
+            "channel_id": "1",
+            "author": {
+                "id": "2",
+                "username": "user",
+                "discriminator": "0001",
+                "avatar": null,
+            },
+            "content": format!("message {}", id),
+            "timestamp": "2021-01-01T00:00:00+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        }))
+        .unwrap()
+    }
+
+    fn test_file(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("weecord-test-{}", std::process::id()))
+            .join(name)
+    }
+
+    #[test]
+    fn keeps_the_newest_messages() {
+        let path = test_file("truncate.json");
+        let messages: Vec<_> = (10..15).map(message).collect();
+        write(&path, &messages, &Stickers::new(), 3).unwrap();
+
+        let (loaded, _) = read(&path);
+        let ids: Vec<_> = loaded.iter().map(|msg| msg.id.0).collect();
+        assert_eq!(ids, vec![12, 13, 14]);
+        assert_eq!(loaded[0].content, "message 12");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keeps_stickers() {
+        let path = test_file("stickers.json");
+        let mut raw = serde_json::to_value(message(5)).unwrap();
+        raw["sticker_items"] = json!([{ "id": "3", "name": "wave", "format_type": 1 }]);
+        let (_, stickers) = stickers::parse_messages(&json!([raw]));
+        write(&path, &[message(4), message(5)], &stickers, 10).unwrap();
+
+        let (loaded, loaded_stickers) = read(&path);
+        assert_eq!(loaded.len(), 2);
+        assert!(!loaded_stickers.contains_key(&MessageId(4)));
+        assert_eq!(loaded_stickers[&MessageId(5)][0].name, "wave");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_caches_are_empty() {
+        let (messages, stickers) = read(&test_file("missing.json"));
+        assert!(messages.is_empty());
+        assert!(stickers.is_empty());
+    }
+}
@@ -1,15 +1,74 @@
-use crate::Discord;
+use crate::{weechat_utils::raw, Discord};
 use crossbeam_channel::{unbounded, Sender};
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 use std::{
-    any::Any, cell::RefCell, collections::VecDeque, mem::transmute, sync::Arc, thread,
-    time::Duration,
+    any::Any,
+    cell::RefCell,
+    collections::VecDeque,
+    mem::transmute,
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use weechat::Weechat;
 
+/// How long queued jobs may run before weechat gets a chance to redraw and handle input
+const JOB_BUDGET: Duration = Duration::from_millis(50);
+
 /// Created upon sync initialization, must not be dropped while the plugin is running
-pub struct SyncHandle(weechat::TimerHook<()>);
+pub struct SyncHandle {
+    // The hook has to be dropped before the pipe is closed
+    _hook: raw::FdHook,
+    _wakeup: Wakeup,
+}
+
+impl Drop for SyncHandle {
+    fn drop(&mut self) {
+        WAKEUP_FD.store(-1, Ordering::SeqCst);
+        WAKEUP_READ_FD.store(-1, Ordering::SeqCst);
+    }
+}
+
+/// A pipe written to when jobs are queued, its read end is watched by weechat
+struct Wakeup {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Wakeup {
+    fn new() -> std::io::Result<Wakeup> {
+        let mut fds = [0; 2];
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            // Neither side may ever block the main thread or a thread queueing a job
+            for &fd in &fds {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        Ok(Wakeup {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Wakeup {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
 
 enum Job {
     Nonblocking(Box<dyn FnOnce(&Discord) + Send>),
@@ -24,12 +83,51 @@ lazy_static! {
     static ref MAIN_THREAD: Arc<Mutex<Option<thread::ThreadId>>> = Arc::new(Mutex::new(None));
 }
 
+/// The write end of the wakeup pipe, `-1` until initialized
+static WAKEUP_FD: AtomicI32 = AtomicI32::new(-1);
+/// The read end of the wakeup pipe
+static WAKEUP_READ_FD: AtomicI32 = AtomicI32::new(-1);
+/// Whether a wakeup was written that the main thread has not handled yet
+static WAKEUP_PENDING: AtomicBool = AtomicBool::new(false);
+
 /// Initialize thread synchronization, this function must be called on the main thread
 pub fn init(weechat: &weechat::Weechat) -> SyncHandle {
     *MAIN_THREAD.lock() = Some(thread::current().id());
 
-    // TODO: Dynamic delay
-    SyncHandle(weechat.hook_timer(Duration::from_millis(25), 0, 0, |_, _, _| tick(), None))
+    let wakeup = Wakeup::new().expect("Unable to create wakeup pipe");
+    let hook =
+        raw::hook_fd_read(weechat, wakeup.read, run_jobs).expect("Unable to hook wakeup pipe");
+    WAKEUP_FD.store(wakeup.write, Ordering::SeqCst);
+    WAKEUP_READ_FD.store(wakeup.read, Ordering::SeqCst);
+    if !JOB_QUEUE.lock().borrow().is_empty() {
+        wake_main();
+    }
+
+    SyncHandle {
+        _hook: hook,
+        _wakeup: wakeup,
+    }
+}
+
+/// Queue a job and wake up the main thread to run it
+fn queue(job: Job) {
+    JOB_QUEUE.lock().borrow_mut().push_back(job);
+    wake_main();
+}
+
+fn wake_main() {
+    let fd = WAKEUP_FD.load(Ordering::SeqCst);
+    // One unhandled wakeup is enough, the main thread drains the whole queue
+    if fd < 0 || WAKEUP_PENDING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    write_wakeup(fd);
+}
+
+fn write_wakeup(fd: RawFd) {
+    unsafe {
+        libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+    }
 }
 
 pub fn on_main<F: 'static + FnOnce(&Discord) + Send>(cb: F) {
@@ -38,10 +136,7 @@ pub fn on_main<F: 'static + FnOnce(&Discord) + Send>(cb: F) {
         cb(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
     } else {
         // queue closure for later
-        JOB_QUEUE
-            .lock()
-            .borrow_mut()
-            .push_back(Job::Nonblocking(Box::new(cb)));
+        queue(Job::Nonblocking(Box::new(cb)));
     }
 }
 
@@ -59,24 +154,48 @@ pub fn on_main_blocking<R: Send, F: FnOnce(&Discord) -> R + Send, ER: 'static +
         cb(unsafe { &crate::__PLUGIN.as_ref().unwrap() })
     } else {
         let (tx, rx) = unbounded();
-        let job = Job::Blocking(Box::new(move |data| Box::new(cb(data))), tx);
-        JOB_QUEUE.lock().borrow_mut().push_back(job);
+        queue(Job::Blocking(Box::new(move |data| Box::new(cb(data))), tx));
 
         let rcv: Box<dyn Any + Send> = rx.recv().expect("rx can't fail");
         *rcv.downcast::<ER>().expect("downcast can't fail")
     }
 }
 
-fn tick() {
-    match JOB_QUEUE.lock().borrow_mut().pop_front() {
-        Some(Job::Nonblocking(cb)) => {
-            (cb)(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
-        },
-        Some(Job::Blocking(cb, tx)) => {
-            let result = (cb)(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
-            let _ = tx.send(result);
-        },
-        None => {},
+/// Run queued jobs until the queue is empty or the time budget is used up
+fn run_jobs() {
+    // Empty the pipe before clearing the flag: clearing it first would let a job queued in
+    // between write a wakeup that is then drained while the flag stays set, and no wakeup would
+    // ever be written again. In this order a job queued after the read writes a new wakeup, at
+    // worst causing one extra call with nothing to do.
+    let fd = WAKEUP_READ_FD.load(Ordering::SeqCst);
+    let mut buf = [0u8; 64];
+    while unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+    WAKEUP_PENDING.store(false, Ordering::SeqCst);
+
+    let start = Instant::now();
+    while start.elapsed() < JOB_BUDGET {
+        // The queue must not be locked while a job runs, jobs may queue more jobs
+        let job = JOB_QUEUE.lock().borrow_mut().pop_front();
+        match job {
+            Some(Job::Nonblocking(cb)) => {
+                (cb)(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
+            },
+            Some(Job::Blocking(cb, tx)) => {
+                let result = (cb)(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
+                let _ = tx.send(result);
+            },
+            None => return,
+        }
+    }
+
+    // Out of time, continue on the next iteration of weechat's main loop. Always write here,
+    // even if another thread set the flag, the pipe must not be left empty with jobs queued
+    if !JOB_QUEUE.lock().borrow().is_empty() {
+        let fd = WAKEUP_FD.load(Ordering::SeqCst);
+        if fd >= 0 {
+            WAKEUP_PENDING.store(true, Ordering::SeqCst);
+            write_wakeup(fd);
+        }
     }
 }
 
@@ -0,0 +1,200 @@
+//! A fixed pool of worker threads for blocking Discord work (REST requests, delays) so events
+//! don't each need their own thread
+//!
+//! Tasks of a channel run one at a time in the order they were spawned, so eg: history and edits
+//! are applied in order, and the tasks still waiting are dropped when its buffer is closed.
+//! Messages being sent to a channel are queued separately so they don't wait for its history.
+use crossbeam_channel::{unbounded, RecvTimeoutError, Sender};
+use lazy_static::lazy_static;
+use parking_lot::{Condvar, Mutex};
+use serenity::model::id::ChannelId;
+use std::{
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+    thread,
+    time::{Duration, Instant},
+};
+
+const WORKERS: usize = 8;
+
+type Task = Box<dyn FnOnce() + Send>;
+type ChannelKey = (String, ChannelId, Lane);
+
+/// The independent queues of a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Lane {
+    Channel,
+    Send,
+}
+
+enum Job {
+    Task(Task),
+    /// Run the next task of a channel
+    Channel(ChannelKey),
+}
+
+#[derive(Default)]
+struct Queue {
+    ready: VecDeque<Job>,
+    /// The waiting tasks of each channel that is queued or running, at most one job of a channel
+    /// is in `ready` or being run at a time
+    channels: HashMap<ChannelKey, VecDeque<Task>>,
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<Queue> = Mutex::new(Queue::default());
+    static ref WORK_AVAILABLE: Condvar = Condvar::new();
+    static ref TIMERS: Mutex<Option<Sender<(Instant, Task)>>> = Mutex::new(None);
+}
+
+static START: Once = Once::new();
+
+fn start() {
+    START.call_once(|| {
+        for i in 0..WORKERS {
+            thread::Builder::new()
+                .name(format!("weecord worker {}", i))
+                .spawn(work)
+                .expect("Unable to name thread");
+        }
+
+        let (tx, rx) = unbounded();
+        *TIMERS.lock() = Some(tx);
+        thread::Builder::new()
+            .name("weecord timers".into())
+            .spawn(move || run_timers(rx))
+            .expect("Unable to name thread");
+    });
+}
+
+fn push(job: Job) {
+    start();
+    QUEUE.lock().ready.push_back(job);
+    WORK_AVAILABLE.notify_one();
+}
+
+/// Run a task on the pool
+pub fn spawn<F: FnOnce() + Send + 'static>(task: F) {
+    push(Job::Task(Box::new(task)));
+}
+
+/// Run a task on the pool after the tasks spawned earlier for the same channel are done
+pub fn spawn_for_channel<F: FnOnce() + Send + 'static>(account: &str, channel: ChannelId, task: F) {
+    spawn_queued((account.to_owned(), channel, Lane::Channel), Box::new(task));
+}
+
+/// Send a message to a channel on the pool after the messages sent to it earlier, independently of
+/// the other tasks of the channel
+pub fn spawn_send<F: FnOnce() + Send + 'static>(account: &str, channel: ChannelId, task: F) {
+    spawn_queued((account.to_owned(), channel, Lane::Send), Box::new(task));
+}
+
+fn spawn_queued(key: ChannelKey, task: Task) {
+    start();
+    let mut queue = QUEUE.lock();
+    match queue.channels.get_mut(&key) {
+        Some(tasks) => tasks.push_back(task),
+        None => {
+            queue
+                .channels
+                .insert(key.clone(), VecDeque::from(vec![task]));
+            queue.ready.push_back(Job::Channel(key));
+            WORK_AVAILABLE.notify_one();
+        },
+    }
+}
+
+/// Run a task on the pool once a delay is over
+pub fn spawn_after<F: FnOnce() + Send + 'static>(delay: Duration, task: F) {
+    start();
+    if let Some(timers) = &*TIMERS.lock() {
+        let _ = timers.send((Instant::now() + delay, Box::new(task)));
+    }
+}
+
+/// Drop the tasks of a channel that have not started yet, messages being sent are still sent
+pub fn cancel_channel(account: &str, channel: ChannelId) {
+    let key = (account.to_owned(), channel, Lane::Channel);
+    if let Some(tasks) = QUEUE.lock().channels.get_mut(&key) {
+        tasks.clear();
+    }
+}
+
+fn work() {
+    loop {
+        let job = {
+            let mut queue = QUEUE.lock();
+            loop {
+                if let Some(job) = queue.ready.pop_front() {
+                    break job;
+                }
+                WORK_AVAILABLE.wait(&mut queue);
+            }
+        };
+
+        match job {
+            Job::Task(task) => run(task),
+            Job::Channel(key) => {
+                let task = QUEUE
+                    .lock()
+                    .channels
+                    .get_mut(&key)
+                    .and_then(VecDeque::pop_front);
+                if let Some(task) = task {
+                    run(task);
+                }
+
+                // Requeue the channel at the back so busy channels don't starve the others
+                let mut queue = QUEUE.lock();
+                if queue
+                    .channels
+                    .get(&key)
+                    .map_or(false, |tasks| !tasks.is_empty())
+                {
+                    queue.ready.push_back(Job::Channel(key));
+                    WORK_AVAILABLE.notify_one();
+                } else {
+                    queue.channels.remove(&key);
+                }
+            },
+        }
+    }
+}
+
+fn run(task: Task) {
+    // A panicking task must not take a worker (or the queue of its channel) down with it
+    if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+        crate::plugin_print("A background task panicked");
+    }
+}
+
+fn run_timers(rx: crossbeam_channel::Receiver<(Instant, Task)>) {
+    let mut timers: Vec<(Instant, Task)> = Vec::new();
+    loop {
+        let now = Instant::now();
+        let mut i = 0;
+        while i < timers.len() {
+            if timers[i].0 <= now {
+                let (_, task) = timers.swap_remove(i);
+                push(Job::Task(task));
+            } else {
+                i += 1;
+            }
+        }
+
+        let next = timers.iter().map(|(due, _)| *due).min();
+        let timer = match next {
+            Some(due) => match rx.recv_timeout(due.saturating_duration_since(now)) {
+                Ok(timer) => timer,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            },
+            None => match rx.recv() {
+                Ok(timer) => timer,
+                Err(_) => return,
+            },
+        };
+        timers.push(timer);
+    }
+}
@@ -138,8 +138,10 @@ impl ChannelExt for Channel {
 }
 
 pub trait BufferExt {
+    fn account(&self) -> String;
     fn channel_id(&self) -> Option<ChannelId>;
     fn guild_id(&self) -> Option<GuildId>;
+    fn parent_channel_id(&self) -> Option<ChannelId>;
 
     fn history_loaded(&self) -> bool;
     fn set_history_loaded(&self);
@@ -149,6 +151,13 @@ pub trait BufferExt {
 }
 
 impl BufferExt for Buffer {
+    /// The account a buffer belongs to, buffers of other plugins belong to the main account
+    fn account(&self) -> String {
+        self.get_localvar("account")
+            .map(Cow::into_owned)
+            .unwrap_or_else(|| crate::config::MAIN_ACCOUNT.to_owned())
+    }
+
     fn channel_id(&self) -> Option<ChannelId> {
         self.get_localvar("channelid")
             .and_then(|ch| ch.parse::<u64>().ok())
@@ -161,6 +170,12 @@ impl BufferExt for Buffer {
             .map(Into::into)
     }
 
+    fn parent_channel_id(&self) -> Option<ChannelId> {
+        self.get_localvar("parent_channelid")
+            .and_then(|ch| ch.parse::<u64>().ok())
+            .map(Into::into)
+    }
+
     fn history_loaded(&self) -> bool {
         self.get_localvar("loaded_history").is_some()
     }
@@ -230,22 +245,54 @@ pub fn parse_id(id: &str) -> Option<GuildOrChannel> {
     }
 }
 
-pub fn get_irc_mode(weechat: &weechat::Weechat) -> bool {
-    crate::upgrade_plugin(weechat).config.irc_mode.value()
+pub fn get_irc_mode(weechat: &weechat::Weechat, account: &str) -> bool {
+    crate::upgrade_plugin(weechat).config.irc_mode(account)
+}
+
+/// Buffers of accounts other than the main account are prefixed with the account name
+fn account_prefix(account: &str) -> String {
+    if account == crate::config::MAIN_ACCOUNT {
+        String::new()
+    } else {
+        format!("{}.", account)
+    }
 }
 
-pub fn buffer_id_for_guild(id: GuildId) -> String {
-    format!("{}", id.0)
+pub fn buffer_id_for_guild(account: &str, id: GuildId) -> String {
+    format!("{}{}", account_prefix(account), id.0)
 }
 
-pub fn buffer_id_for_channel(guild_id: Option<GuildId>, channel_id: ChannelId) -> String {
+pub fn buffer_id_for_channel(
+    account: &str,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> String {
     if let Some(guild_id) = guild_id {
-        format!("{}.{}", guild_id, channel_id.0)
+        format!("{}{}.{}", account_prefix(account), guild_id, channel_id.0)
     } else {
-        format!("Private.{}", channel_id.0)
+        format!("{}Private.{}", account_prefix(account), channel_id.0)
     }
 }
 
+pub fn buffer_id_for_pins(account: &str, channel_id: ChannelId) -> String {
+    format!("{}Pins.{}", account_prefix(account), channel_id.0)
+}
+
+/// The name of the buffer messages of a channel are shown in
+pub fn buffer_name_for_channel(
+    cache: &CacheRwLock,
+    account: &str,
+    channel_id: ChannelId,
+) -> String {
+    let guild_id = match cache.read().channel(&channel_id) {
+        Some(Channel::Guild(channel)) => Some(channel.read().guild_id),
+        Some(_) => None,
+        // Threads are not cached, anything else is assumed to be a private channel
+        None => crate::discord::threads::get(account, channel_id).map(|thread| thread.guild_id),
+    };
+    buffer_id_for_channel(account, guild_id, channel_id)
+}
+
 pub unsafe fn buffer_from_ptr(buffer_ptr: *mut std::ffi::c_void) -> Buffer {
     Buffer::from_ptr(
         crate::__PLUGIN.as_mut().unwrap().weechat.as_ptr(),
@@ -253,14 +300,6 @@ pub unsafe fn buffer_from_ptr(buffer_ptr: *mut std::ffi::c_void) -> Buffer {
     )
 }
 
-pub fn buffer_is_muted(buffer: &Buffer) -> bool {
-    if let Some(muted) = buffer.get_localvar("muted") {
-        muted == "1"
-    } else {
-        false
-    }
-}
-
 pub fn search_channel(
     cache: &CacheRwLock,
     guild_name: &str,
@@ -439,45 +478,83 @@ pub fn expand_guild_emojis(cache: &CacheRwLock, guild_id: Option<GuildId>, input
         static ref EMOJI_SYNTAX: Regex = Regex::new(r"(.?):(\w+):").unwrap();
     }
 
-    let emojis = EMOJI_SYNTAX.captures_iter(input);
-    if let Some(guild) = guild_id.and_then(|id| id.to_guild_cached(cache)) {
-        let guild = guild.read();
-        for emoji_match in emojis {
-            if let Some(prefix) = emoji_match.get(1) {
-                if prefix.as_str() == "\\" {
-                    continue;
-                }
+    for emoji_match in EMOJI_SYNTAX.captures_iter(input) {
+        if let Some(prefix) = emoji_match.get(1) {
+            if prefix.as_str() == "\\" {
+                continue;
             }
-            if let Some(emoji_match) = emoji_match.get(2) {
-                let emoji_name = emoji_match.as_str();
-                if let Some(guild_emoji) =
-                    guild.emojis.values().find(|emoji| emoji.name == emoji_name)
-                {
-                    out = out.replace(&format!(":{}:", emoji_name), &guild_emoji.mention());
-                }
+        }
+        if let Some(emoji_match) = emoji_match.get(2) {
+            let emoji_name = emoji_match.as_str();
+            if let Some(guild_emoji) = find_guild_emoji(cache, guild_id, emoji_name) {
+                out = out.replace(&format!(":{}:", emoji_name), &guild_emoji.mention());
             }
         }
     }
     out
 }
 
-/// Remove the guild id from global emojis
-pub fn clean_emojis(input: &str) -> String {
-    let mut out = String::from(input);
+/// Find a custom emoji of a guild by name
+pub fn find_guild_emoji(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    name: &str,
+) -> Option<Emoji> {
+    let guild = guild_id?.to_guild_cached(cache)?;
+    let guild = guild.read();
+    guild
+        .emojis
+        .values()
+        .find(|emoji| emoji.name == name)
+        .cloned()
+}
+
+/// Resolve the emoji of a reaction, shortcodes are first looked up in the custom emoji of the guild
+/// and then in the unicode emoji
+pub fn resolve_reaction_emoji(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    emoji: &parsing::ReactionEmoji,
+) -> Option<ReactionType> {
+    match emoji {
+        parsing::ReactionEmoji::Unicode(emoji) => Some(ReactionType::Unicode(emoji.to_string())),
+        parsing::ReactionEmoji::Shortcode(name) => {
+            if let Some(custom) = find_guild_emoji(cache, guild_id, name) {
+                Some(ReactionType::Custom {
+                    animated: custom.animated,
+                    id: custom.id,
+                    name: Some(custom.name),
+                })
+            } else {
+                crate::emoji::from_shortcode(name)
+                    .map(|emoji| ReactionType::Unicode(emoji.to_owned()))
+            }
+        },
+    }
+}
 
+/// Replace custom emoji, including animated ones, with their `:name:`, optionally followed by the
+/// url of their image
+pub fn clean_emojis(input: &str, show_url: bool) -> String {
     lazy_static! {
-        static ref GLOBAL_EMOJI: Regex = Regex::new(r"<:(.*?):(\d*?)>").unwrap();
+        static ref CUSTOM_EMOJI: Regex = Regex::new(r"<(a?):(\w+):(\d+)>").unwrap();
     }
 
-    let global_emoji = GLOBAL_EMOJI.captures_iter(input);
-    for emoji_match in global_emoji {
-        let emoji_name = emoji_match.get(1).unwrap().as_str();
-
-        out = out.replace(
-            emoji_match.get(0).unwrap().as_str(),
-            &format!(":{}:", emoji_name),
-        );
-    }
+    CUSTOM_EMOJI
+        .replace_all(input, |caps: &regex::Captures| {
+            let name = &caps[2];
+            if show_url {
+                let animated = !caps[1].is_empty();
+                format!(":{}: ({})", name, emoji_url(&caps[3], animated))
+            } else {
+                format!(":{}:", name)
+            }
+        })
+        .into_owned()
+}
 
-    out
+/// The url of the image of a custom emoji
+pub fn emoji_url(id: &str, animated: bool) -> String {
+    let extension = if animated { "gif" } else { "png" };
+    format!("https://cdn.discordapp.com/emojis/{}.{}", id, extension)
 }
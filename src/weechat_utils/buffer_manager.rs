@@ -1,4 +1,4 @@
-use crate::weechat_utils::message_manager::MessageManager;
+use crate::{message_cache, utils::BufferExt, weechat_utils::message_manager::MessageManager};
 use std::{cell::RefCell, collections::HashMap, sync::Arc};
 use weechat::Weechat;
 
@@ -32,6 +32,53 @@ impl BufferManager {
         }
     }
 
+    /// The names of all tracked buffers
+    pub fn buffer_names(&self) -> Vec<String> {
+        self.buffers.borrow().keys().cloned().collect()
+    }
+
+    /// Write the messages of every changed buffer to the message cache in the background
+    pub fn save_message_cache(&self, max_messages: usize) {
+        if max_messages == 0 {
+            return;
+        }
+
+        for buffer in self.buffers.borrow().values() {
+            if !buffer.take_dirty() {
+                continue;
+            }
+            if let Some(channel) = buffer.channel_id() {
+                message_cache::store_later(
+                    &self.weechat,
+                    &buffer.account(),
+                    channel,
+                    buffer.messages(),
+                    buffer.stickers(),
+                    max_messages,
+                );
+            }
+        }
+    }
+
+    /// Write the messages of every changed buffer to the message cache and wait until they are
+    /// written, background tasks don't finish once the plugin is unloaded
+    pub fn flush_message_cache(&self, max_messages: usize) {
+        self.save_message_cache(max_messages);
+        if let Err(e) = message_cache::flush() {
+            self.weechat
+                .print(&format!("discord: Unable to write message cache: {}", e));
+        }
+    }
+
+    /// Close a buffer and stop tracking it
+    pub fn close_buffer(&self, name: &str) {
+        self.buffers.borrow_mut().remove(name);
+
+        if let Some(buffer) = self.weechat.buffer_search("weecord", name) {
+            buffer.close();
+        }
+    }
+
     pub fn get_or_create_buffer(&self, name: &str) -> Arc<MessageManager> {
         if let Some(buffer) = self.buffers.borrow().get(name) {
             return Arc::clone(buffer);
@@ -1,4 +1,11 @@
-use crate::utils::BufferExt;
+use crate::{
+    config::DeletedMessages,
+    discord::stickers::{Sticker, Stickers},
+    utils::BufferExt,
+    weechat_utils::raw,
+};
+use chrono::Utc;
+use serde_json::Value;
 use serenity::{
     cache::CacheRwLock,
     model::{
@@ -6,14 +13,49 @@ use serenity::{
         id::{MessageId, UserId},
     },
 };
-use std::{cell::RefCell, ops::Deref, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ops::Deref,
+    sync::Arc,
+};
 use weechat::Buffer;
 
+/// A message sent from a buffer that Discord has not echoed back yet
+#[derive(Debug, Clone)]
+pub struct PendingMessage {
+    /// Sent along with the message, the echoed message carries the same nonce
+    pub nonce: String,
+    /// The display name of the current user when the message was sent
+    pub author: String,
+    /// The text as it was typed, shown until the message arrives
+    pub text: String,
+    /// The text sent to Discord, with mentions and emoji expanded
+    pub content: String,
+    /// The message this is a reply to
+    pub reply_to: Option<MessageId>,
+    pub failed: bool,
+}
+
 /// MessageRenderer wraps a weechat buffer and facilitates editing the buffer and drawing the
 /// messages
 pub struct MessageManager {
     buffer: Buffer,
     messages: Arc<RefCell<Vec<Message>>>,
+    /// Whether the messages have changed since they were last written to the message cache
+    dirty: Cell<bool>,
+    /// Messages that have been deleted but are still shown, and how they are shown
+    deleted: RefCell<HashMap<MessageId, DeletedMessages>>,
+    /// Previous versions of edited messages, oldest first
+    edits: RefCell<HashMap<MessageId, Vec<Message>>>,
+    /// The newest message that has been read, the read marker is drawn after it
+    last_read: Cell<Option<MessageId>>,
+    /// Messages being sent, shown after all other messages
+    pending: RefCell<Vec<PendingMessage>>,
+    /// Whether the pending messages are printed, clearing the buffer removes them
+    pending_printed: Cell<bool>,
+    /// Stickers of the loaded messages, serenity drops them
+    stickers: RefCell<Stickers>,
 }
 
 impl MessageManager {
@@ -22,50 +64,374 @@ impl MessageManager {
         MessageManager {
             buffer,
             messages: Arc::new(RefCell::new(Vec::new())),
+            dirty: Cell::new(false),
+            deleted: RefCell::new(HashMap::new()),
+            edits: RefCell::new(HashMap::new()),
+            last_read: Cell::new(None),
+            pending: RefCell::new(Vec::new()),
+            pending_printed: Cell::new(false),
+            stickers: RefCell::new(Stickers::new()),
         }
     }
 
-    /// Format and print message to the buffer
-    fn print_msg(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<UserId> {
-        let weechat = self.buffer.get_weechat();
-        let maybe_guild = self.buffer.guild_id();
-        let (prefix, content, unknown_users) =
-            formatting_utils::render_msg(cache, &weechat, msg, maybe_guild);
+    /// Format and print message to the buffer, `previous` is the message printed before it
+    fn print_msg(
+        &self,
+        cache: &CacheRwLock,
+        msg: &Message,
+        previous: Option<MessageId>,
+        notify: bool,
+    ) -> Vec<UserId> {
+        // The read marker goes before the first unread message
+        if let Some(last_read) = self.last_read.get() {
+            if msg.id > last_read && previous.map_or(true, |previous| previous <= last_read) {
+                self.buffer.mark_read();
+            }
+        }
+        let (prefix, content, unknown_users) = self.render_msg(cache, msg);
         self.buffer.print_tags_dated(
             msg.timestamp.timestamp(),
-            &formatting_utils::msg_tags(cache, msg, notify).join(","),
+            &self.msg_tags(cache, msg, notify),
             &format!("{}\t{}", prefix, content),
         );
         unknown_users
     }
 
+    /// Render a message along with the state only known to this buffer
+    fn render_msg(&self, cache: &CacheRwLock, msg: &Message) -> (String, String, Vec<UserId>) {
+        let weechat = self.buffer.get_weechat();
+        let show_edit_history = crate::upgrade_plugin(&weechat)
+            .config
+            .show_edit_history
+            .value();
+
+        let referenced = self.referenced_message(msg);
+        let edits = self.edits.borrow();
+        let stickers = self.stickers.borrow();
+        let state = formatting_utils::MessageState {
+            referenced: referenced.as_ref(),
+            deleted: self.deleted.borrow().get(&msg.id).copied(),
+            previous_versions: match edits.get(&msg.id) {
+                Some(previous) if show_edit_history => previous.as_slice(),
+                _ => &[],
+            },
+            stickers: stickers.get(&msg.id).map_or(&[], Vec::as_slice),
+        };
+
+        let account = self.buffer.account();
+        formatting_utils::render_msg(
+            cache,
+            &weechat,
+            &account,
+            msg,
+            &state,
+            self.buffer.guild_id(),
+        )
+    }
+
+    fn msg_tags(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> String {
+        let deleted = self.deleted.borrow().contains_key(&msg.id);
+        let weechat = self.buffer.get_weechat();
+        let account = self.buffer.account();
+        let guild = self.buffer.guild_id();
+        formatting_utils::msg_tags(cache, &weechat, &account, msg, guild, notify, deleted).join(",")
+    }
+
+    /// Find the message a reply refers to, if it has been loaded into this buffer
+    fn referenced_message(&self, msg: &Message) -> Option<Message> {
+        let id = msg.message_reference.as_ref()?.message_id?;
+        self.messages
+            .borrow()
+            .iter()
+            .find(|it| it.id == id)
+            .cloned()
+    }
+
     /// Clear the buffer and reprint all messages
     pub fn redraw_buffer(&self, cache: &CacheRwLock) {
         self.buffer.clear();
+        let mut previous = None;
         for message in self.messages.borrow().iter() {
-            self.print_msg(cache, &message, false);
+            self.print_msg(cache, &message, previous, false);
+            previous = Some(message.id);
+        }
+        self.mark_read_if_caught_up();
+        for pending in self.pending.borrow().iter() {
+            self.print_pending(pending);
         }
     }
 
-    /// Removes all content from the buffer
+    /// Move the read marker forward to after a message
+    pub fn set_last_read(&self, id: MessageId) {
+        if self
+            .last_read
+            .get()
+            .map_or(true, |last_read| last_read < id)
+        {
+            self.last_read.set(Some(id));
+        }
+    }
+
+    /// Draw the read marker after the last message if all messages have been read
+    pub fn mark_read_if_caught_up(&self) {
+        let last_message = self.last_message_id();
+        if last_message.is_some() && last_message <= self.last_read.get() {
+            self.buffer.mark_read();
+        }
+    }
+
+    /// Whether a message highlights the current user
+    pub fn mentions_current_user(&self, cache: &CacheRwLock, msg: &Message) -> bool {
+        let weechat = self.buffer.get_weechat();
+        let account = self.buffer.account();
+        formatting_utils::is_highlight(cache, &weechat, &account, msg, self.buffer.guild_id())
+    }
+
+    /// Removes all content from the buffer, messages still being sent (or that failed to send) are
+    /// kept and printed again once history is printed
     pub fn clear(&self) {
         self.messages.borrow_mut().clear();
+        self.deleted.borrow_mut().clear();
+        self.edits.borrow_mut().clear();
+        self.stickers.borrow_mut().clear();
+        self.dirty.set(false);
+        self.pending_printed.set(false);
         self.buffer.clear();
     }
 
-    /// Add a message to the end of a buffer (chronologically)
+    /// All messages in the buffer that have not been deleted, oldest first
+    pub fn messages(&self) -> Vec<Message> {
+        let deleted = self.deleted.borrow();
+        self.messages
+            .borrow()
+            .iter()
+            .filter(|msg| !deleted.contains_key(&msg.id))
+            .cloned()
+            .collect()
+    }
+
+    /// The id of the newest message in the buffer
+    pub fn last_message_id(&self) -> Option<MessageId> {
+        self.messages.borrow().last().map(|msg| msg.id)
+    }
+
+    /// Get a loaded message by id
+    pub fn get_message(&self, id: &MessageId) -> Option<Message> {
+        self.messages
+            .borrow()
+            .iter()
+            .find(|it| &it.id == id)
+            .cloned()
+    }
+
+    /// Whether a message has been loaded, newer messages are checked first
+    fn has_message(&self, id: MessageId) -> bool {
+        self.messages.borrow().iter().rev().any(|it| it.id == id)
+    }
+
+    /// Previous versions of a message that were seen while it was loaded, oldest first
+    pub fn edit_history(&self, id: MessageId) -> Vec<Message> {
+        self.edits.borrow().get(&id).cloned().unwrap_or_default()
+    }
+
+    /// Remember the stickers of messages that are about to be added
+    pub fn add_stickers(&self, stickers: Stickers) {
+        self.stickers.borrow_mut().extend(stickers);
+    }
+
+    /// Set the stickers of a loaded message and redraw it
+    pub fn set_stickers(&self, cache: &CacheRwLock, id: MessageId, stickers: Vec<Sticker>) {
+        self.stickers.borrow_mut().insert(id, stickers);
+        if let Some(msg) = self.get_message(&id) {
+            self.replace_message(cache, &id, &msg, Some(&msg));
+        }
+    }
+
+    /// The stickers of the loaded messages
+    pub fn stickers(&self) -> Stickers {
+        self.stickers.borrow().clone()
+    }
+
+    /// Returns true if the messages changed since the last call
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.replace(false)
+    }
+
+    /// Add a message to the end of a buffer (chronologically), or in place of the pending message
+    /// it was sent as
     pub fn add_message(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<UserId> {
-        let unknown_users = self.print_msg(cache, msg, notify);
+        if let Some(nonce) = message_nonce(msg) {
+            if let Some(pending) = self.take_pending(&nonce) {
+                return self.replace_pending(cache, &pending, msg);
+            }
+        }
+        // Sent from this buffer and already confirmed by the response to the request, before it
+        // was echoed back or fetched with history
+        if self.has_message(msg.id) {
+            return Vec::new();
+        }
+        let unknown_users = self.print_msg(cache, msg, self.last_message_id(), notify);
         self.messages.borrow_mut().push(msg.clone());
+        self.dirty.set(true);
         unknown_users
     }
 
+    /// Print a message that is being sent, it is replaced once Discord echoes it back
+    pub fn add_pending(&self, pending: PendingMessage) {
+        self.print_pending(&pending);
+        self.pending.borrow_mut().push(pending);
+    }
+
+    /// Mark a pending message as failed to send, or as being sent again
+    pub fn set_pending_failed(&self, nonce: &str, failed: bool) {
+        let pending = match self
+            .pending
+            .borrow_mut()
+            .iter_mut()
+            .find(|pending| pending.nonce == nonce)
+        {
+            Some(pending) => {
+                pending.failed = failed;
+                pending.clone()
+            },
+            None => return,
+        };
+
+        let weechat = self.buffer.get_weechat();
+        let (_, content) = formatting_utils::render_pending(&weechat, &pending);
+        self.update_tagged_lines(
+            &formatting_utils::pending_tag(nonce),
+            &content,
+            &formatting_utils::pending_tags(nonce),
+        );
+    }
+
+    /// Replace a pending message with the message Discord created from it, has no effect if it was
+    /// already replaced by the echoed message
+    pub fn confirm_pending(&self, cache: &CacheRwLock, nonce: &str, msg: &Message) -> Vec<UserId> {
+        match self.take_pending(nonce) {
+            Some(pending) => self.replace_pending(cache, &pending, msg),
+            None => Vec::new(),
+        }
+    }
+
+    /// Whether there are messages being sent or that failed to send
+    pub fn has_pending(&self) -> bool {
+        !self.pending.borrow().is_empty()
+    }
+
+    /// Messages that could not be sent, oldest first
+    pub fn failed_messages(&self) -> Vec<PendingMessage> {
+        self.pending
+            .borrow()
+            .iter()
+            .filter(|pending| pending.failed)
+            .cloned()
+            .collect()
+    }
+
+    /// Print the messages being sent after history that was just printed, the buffer is only
+    /// redrawn if they were already printed before some of that history
+    pub fn print_pending_after_history(&self, cache: &CacheRwLock, printed_history: bool) {
+        if !self.pending_printed.get() {
+            for pending in self.pending.borrow().iter() {
+                self.print_pending(pending);
+            }
+        } else if printed_history && self.has_pending() {
+            self.redraw_buffer(cache);
+        }
+    }
+
+    /// Stop tracking the messages that failed to send and remove them from the buffer, returns how
+    /// many there were
+    pub fn remove_failed(&self, cache: &CacheRwLock) -> usize {
+        let count = {
+            let mut pending = self.pending.borrow_mut();
+            let count = pending.len();
+            pending.retain(|pending| !pending.failed);
+            count - pending.len()
+        };
+        // Lines can't be removed from a buffer
+        if count > 0 {
+            self.redraw_buffer(cache);
+        }
+        count
+    }
+
+    fn print_pending(&self, pending: &PendingMessage) {
+        self.pending_printed.set(true);
+        let weechat = self.buffer.get_weechat();
+        let (prefix, content) = formatting_utils::render_pending(&weechat, pending);
+        self.buffer.print_tags_dated(
+            Utc::now().timestamp(),
+            &formatting_utils::pending_tags(&pending.nonce),
+            &format!("{}\t{}", prefix, content),
+        );
+    }
+
+    /// Stop tracking a pending message, if it is still pending
+    fn take_pending(&self, nonce: &str) -> Option<PendingMessage> {
+        let mut pending = self.pending.borrow_mut();
+        let index = pending.iter().position(|pending| pending.nonce == nonce)?;
+        Some(pending.remove(index))
+    }
+
+    /// Overwrite the printed lines of a pending message with the message Discord echoed back
+    fn replace_pending(
+        &self,
+        cache: &CacheRwLock,
+        pending: &PendingMessage,
+        msg: &Message,
+    ) -> Vec<UserId> {
+        // Already printed with history that was fetched while it was being sent
+        if self.has_message(msg.id) {
+            self.redraw_buffer(cache);
+            return Vec::new();
+        }
+        self.messages.borrow_mut().push(msg.clone());
+        self.dirty.set(true);
+
+        let (_, content, unknown_users) = self.render_msg(cache, msg);
+        let tags = self.msg_tags(cache, msg, false);
+        if !self.update_tagged_lines(
+            &formatting_utils::pending_tag(&pending.nonce),
+            &content,
+            &tags,
+        ) {
+            self.redraw_buffer(cache);
+        }
+        unknown_users
+    }
+
+    /// Add older messages to the start of a buffer (chronologically), this requires a redraw
+    pub fn prepend_messages(&self, cache: &CacheRwLock, msgs: &[Message]) {
+        if msgs.is_empty() {
+            return;
+        }
+        {
+            let mut messages = self.messages.borrow_mut();
+            let newer = std::mem::replace(&mut *messages, msgs.to_vec());
+            messages.extend(newer);
+        }
+        self.dirty.set(true);
+        self.redraw_buffer(cache);
+    }
+
+    /// The id of the oldest message in the buffer
+    pub fn oldest_message_id(&self) -> Option<MessageId> {
+        self.messages.borrow().first().map(|msg| msg.id)
+    }
+
     // Overwrite a previously printed message, has no effect if the message does not exist
+    //
+    // `previous` is the version of the message before this update if it is known, otherwise the
+    // printed version is kept as edit history
     pub fn replace_message(
         &self,
         cache: &CacheRwLock,
         id: &MessageId,
         msg: &Message,
+        previous: Option<&Message>,
     ) -> Vec<UserId> {
         if let Some(old_msg) = self
             .messages
@@ -73,40 +439,116 @@ impl MessageManager {
             .iter_mut()
             .find(|it| &it.id == id)
         {
+            let previous = previous.unwrap_or(old_msg);
+            if previous.content != msg.content {
+                self.edits
+                    .borrow_mut()
+                    .entry(*id)
+                    .or_default()
+                    .push(previous.clone());
+            }
             *old_msg = msg.clone();
+            self.dirty.set(true);
+        } else {
+            return Vec::new();
         }
-        // Using hdata to edit the line might be more efficient Would still use redrawing as a fall
-        // back in the event that the edit has a different amount of lines
-        self.redraw_buffer(cache);
-        let (_, _, unknown_users) = formatting_utils::render_msg(
-            cache,
-            &self.buffer.get_weechat(),
-            msg,
-            self.buffer.guild_id(),
-        );
+
+        // Replies show a preview of the message, so they need to be updated as well
+        let replies: Vec<_> = self
+            .messages
+            .borrow()
+            .iter()
+            .filter(|it| {
+                it.message_reference
+                    .as_ref()
+                    .and_then(|reference| reference.message_id)
+                    == Some(*id)
+            })
+            .cloned()
+            .collect();
+
+        let updated = std::iter::once(msg)
+            .chain(replies.iter())
+            .all(|msg| self.update_lines(cache, msg));
+        if !updated {
+            self.redraw_buffer(cache);
+        }
+
+        let (_, _, unknown_users) = self.render_msg(cache, msg);
         unknown_users
     }
 
+    /// Edit the printed lines of a message in place, returns false if this is not possible because
+    /// the amount of lines changed (or the message was never printed)
+    fn update_lines(&self, cache: &CacheRwLock, msg: &Message) -> bool {
+        let (_, content, _) = self.render_msg(cache, msg);
+        let tags = self.msg_tags(cache, msg, false);
+        self.update_tagged_lines(&formatting_utils::msg_id_tag(msg.id), &content, &tags)
+    }
+
+    /// Replace the text and tags of the lines printed with a tag, returns false if the amount of
+    /// lines differs
+    ///
+    /// Lines keep the notify tag they were printed with, so updates don't change what notified
+    fn update_tagged_lines(&self, tag: &str, content: &str, tags: &str) -> bool {
+        let weechat = self.buffer.get_weechat();
+        let lines = raw::lines_with_tag(&weechat, &self.buffer.get_name(), tag);
+        let new_lines: Vec<_> = content.split('\n').collect();
+        if lines.is_empty() || lines.len() != new_lines.len() {
+            return false;
+        }
+
+        lines.into_iter().zip(new_lines).all(|(line, text)| {
+            let tags = formatting_utils::keep_notify_tag(&raw::line_tags(&weechat, line), tags);
+            raw::update_line(&weechat, line, text, &tags)
+        })
+    }
+
     /// Delete a previously printed message, has no effect if the message does not exist
-    pub fn delete_message(&self, cache: &CacheRwLock, id: &MessageId) -> Vec<UserId> {
+    ///
+    /// Unless `mode` is `Hide` the message stays in the buffer, marked as deleted
+    pub fn delete_message(
+        &self,
+        cache: &CacheRwLock,
+        id: &MessageId,
+        mode: DeletedMessages,
+    ) -> Vec<UserId> {
         let index = self.messages.borrow().iter().position(|it| &it.id == id);
-        let mut unknown_users = Vec::new();
-        if let Some(index) = index {
-            let msg = self.messages.borrow_mut().remove(index);
-            unknown_users = formatting_utils::render_msg(
-                cache,
-                &self.buffer.get_weechat(),
-                &msg,
-                self.buffer.guild_id(),
-            )
-            .2;
+        let index = match index {
+            Some(index) => index,
+            None => return Vec::new(),
         };
-        // Performance, see comment above
-        self.redraw_buffer(cache);
+        self.dirty.set(true);
+
+        if mode == DeletedMessages::Hide {
+            let msg = self.messages.borrow_mut().remove(index);
+            self.deleted.borrow_mut().remove(id);
+            self.edits.borrow_mut().remove(id);
+            let (_, _, unknown_users) = self.render_msg(cache, &msg);
+            // Lines can't be removed from a buffer
+            self.redraw_buffer(cache);
+            return unknown_users;
+        }
+
+        self.deleted.borrow_mut().insert(*id, mode);
+        let msg = self.messages.borrow()[index].clone();
+        if !self.update_lines(cache, &msg) {
+            self.redraw_buffer(cache);
+        }
+        let (_, _, unknown_users) = self.render_msg(cache, &msg);
         unknown_users
     }
 }
 
+/// The nonce a message was sent with, if any
+fn message_nonce(msg: &Message) -> Option<String> {
+    match &msg.nonce {
+        Value::String(nonce) => Some(nonce.clone()),
+        Value::Number(nonce) => Some(nonce.to_string()),
+        _ => None,
+    }
+}
+
 impl Deref for MessageManager {
     type Target = Buffer;
 
@@ -116,21 +558,53 @@ impl Deref for MessageManager {
 }
 
 mod formatting_utils {
+    use super::PendingMessage;
     use crate::{
-        discord::formatting,
-        utils::{colorize_string, format_nick_color},
+        config::DeletedMessages,
+        discord::{formatting, guild_settings, stickers::Sticker, threads},
+        utils::{colorize_string, format_nick_color, ChannelExt},
     };
+    use lazy_static::lazy_static;
+    use regex::{Captures, Regex};
     use serenity::{
         cache::CacheRwLock,
         model::{
             channel::{Channel, Message},
-            id::{GuildId, UserId},
+            id::{ChannelId, GuildId, MessageId, RoleId, UserId},
         },
     };
     use std::str::FromStr;
     use weechat::{ConfigOption, Weechat};
 
-    pub fn msg_tags(cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<String> {
+    /// Maximum amount of characters shown in the preview of a replied to message
+    const REPLY_PREVIEW_LENGTH: usize = 60;
+
+    /// State of a message that is tracked by the buffer rather than Discord
+    pub struct MessageState<'a> {
+        /// The message being replied to, if it is loaded
+        pub referenced: Option<&'a Message>,
+        /// How the message is shown if it has been deleted
+        pub deleted: Option<DeletedMessages>,
+        /// Previous versions to show above the current text, oldest first
+        pub previous_versions: &'a [Message],
+        pub stickers: &'a [Sticker],
+    }
+
+    /// The tag used to find the printed lines of a message
+    pub fn msg_id_tag(id: MessageId) -> String {
+        format!("discord_msg_{}", id.0)
+    }
+
+    /// Build the tags of a message, so triggers, filters and scripts can act on specific messages
+    pub fn msg_tags(
+        cache: &CacheRwLock,
+        weechat: &Weechat,
+        account: &str,
+        msg: &Message,
+        guild: Option<GuildId>,
+        notify: bool,
+        deleted: bool,
+    ) -> Vec<String> {
         let is_private = if let Some(channel) = msg.channel(cache) {
             if let Channel::Private(_) = channel {
                 true
@@ -141,7 +615,9 @@ mod formatting_utils {
             false
         };
 
-        let self_mentioned = msg.mentions_user_id(cache.read().user.id);
+        let current_user = cache.read().user.id;
+        let self_mentioned = is_highlight(cache, weechat, account, msg, guild);
+        let is_own = msg.author.id == current_user;
 
         let mut tags = Vec::new();
         if notify {
@@ -156,24 +632,105 @@ mod formatting_utils {
             tags.push("notify_none");
         }
 
-        tags.into_iter().map(ToString::to_string).collect()
+        use serenity::model::channel::MessageType::*;
+        let (kind, log_level) = match msg.kind {
+            Regular => ("regular", "log1"),
+            GroupRecipientAddition => ("recipient_add", "log4"),
+            GroupRecipientRemoval => ("recipient_remove", "log4"),
+            GroupCallCreation => ("call", "log3"),
+            GroupNameUpdate => ("name_update", "log3"),
+            GroupIconUpdate => ("icon_update", "log3"),
+            PinsAdd => ("pins_add", "log3"),
+            MemberJoin => ("member_join", "log4"),
+            NitroBoost => ("nitro_boost", "log3"),
+            NitroTier1 | NitroTier2 | NitroTier3 => ("nitro_tier", "log3"),
+            __Nonexhaustive => ("unknown", "log3"),
+        };
+        if msg.message_reference.is_some() {
+            tags.push("discord_reply");
+        }
+        if msg.author.bot {
+            tags.push("discord_bot");
+        }
+        if msg.edited_timestamp.is_some() {
+            tags.push("discord_edited");
+        }
+        if deleted {
+            tags.push("discord_deleted");
+        }
+        if is_own {
+            tags.push("self_msg");
+            tags.push("no_highlight");
+        }
+        tags.push(log_level);
+
+        let nick = author_display_name(cache, msg, guild);
+        let mut tags: Vec<_> = tags.into_iter().map(ToString::to_string).collect();
+        tags.push(msg_id_tag(msg.id));
+        tags.push(format!("discord_author_{}", msg.author.id.0));
+        tags.push(format!("discord_channel_{}", msg.channel_id.0));
+        if let Some(guild) = guild {
+            tags.push(format!("discord_guild_{}", guild.0));
+        }
+        tags.push(format!("discord_kind_{}", kind));
+        tags.push(format!(
+            "nick_{}",
+            parsing::weechat_arg_strip(&nick).replace(',', "_")
+        ));
+        tags
     }
 
     pub fn render_msg(
         cache: &CacheRwLock,
         weechat: &Weechat,
+        account: &str,
         msg: &Message,
+        state: &MessageState,
         guild: Option<GuildId>,
     ) -> (String, String, Vec<UserId>) {
         let opts = serenity::utils::ContentSafeOptions::new()
             .clean_here(false)
             .clean_everyone(false)
-            .clean_user(false);
+            .clean_user(false)
+            .clean_role(false)
+            .clean_channel(false);
 
         let mut msg_content = serenity::utils::content_safe(&cache, &msg.content, &opts);
-        msg_content = crate::utils::clean_emojis(&msg_content);
+        msg_content = clean_roles(cache, Some(weechat), &msg_content, guild);
+        msg_content = clean_channels(cache, account, &msg_content);
+        let show_emoji_urls = crate::upgrade_plugin(weechat).config.emoji_urls.value();
+        msg_content = format_emoji(
+            weechat,
+            &crate::utils::clean_emojis(&msg_content, show_emoji_urls),
+        );
         let unknown_users = clean_users(cache, &mut msg_content, true, guild);
 
+        // Markdown would interfere with the strikethrough, so deleted messages are shown as plain
+        // text
+        let struck = if state.deleted == Some(DeletedMessages::Strike) {
+            let mut struck = msg_content
+                .lines()
+                .chain(msg.attachments.iter().map(|a| a.proxy_url.as_str()))
+                .map(|line| {
+                    format!(
+                        "{}~~{}~~{}",
+                        weechat.color("red"),
+                        line,
+                        weechat.color("reset")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            struck.push_str(&format!(
+                "{} (deleted){}",
+                weechat.color("8"),
+                weechat.color("reset")
+            ));
+            Some(struck)
+        } else {
+            None
+        };
+
         if msg.edited_timestamp.is_some() {
             let edited_text = weechat.color("8").into_owned()
                 + " (edited)"
@@ -181,6 +738,14 @@ mod formatting_utils {
             msg_content.push_str(&edited_text);
         }
 
+        if state.deleted == Some(DeletedMessages::Mark) {
+            msg_content.push_str(&format!(
+                "{} (deleted){}",
+                weechat.color("red"),
+                weechat.color("reset")
+            ));
+        }
+
         for attachement in &msg.attachments {
             if !msg_content.is_empty() {
                 msg_content.push('\n');
@@ -188,6 +753,19 @@ mod formatting_utils {
             msg_content.push_str(&attachement.proxy_url);
         }
 
+        for sticker in state.stickers {
+            if !msg_content.is_empty() {
+                msg_content.push('\n');
+            }
+            msg_content.push_str(&format!(
+                "{}[Sticker: {}]{} {}",
+                weechat.color("8"),
+                sticker.name,
+                weechat.color("reset"),
+                sticker.url()
+            ));
+        }
+
         for embed in &msg.embeds {
             if !msg_content.is_empty() {
                 msg_content.push('\n');
@@ -250,7 +828,7 @@ mod formatting_utils {
         for reaction in &msg.reactions {
             match &reaction.reaction_type {
                 ReactionType::Custom { name, .. } => name.clone(),
-                ReactionType::Unicode(s) => Some(s.clone()),
+                ReactionType::Unicode(s) => Some(format_emoji(weechat, s)),
                 _ => None,
             }
             .map(|reaction_string| {
@@ -262,38 +840,19 @@ mod formatting_utils {
             msg_content.push('\n');
         }
 
-        let mut prefix = String::new();
-
-        if let Some(nick_prefix) = weechat.get_string_option("weechat.look.nick_prefix") {
-            if let Some(color) = weechat.get_string_option("weechat.color.chat_nick_prefix") {
-                prefix.push_str(&colorize_string(
-                    weechat,
-                    &color.value(),
-                    &nick_prefix.value(),
-                ))
-            }
-        }
-
         let author = format_nick_color(weechat, &author_display_name(cache, &msg, guild));
-        prefix.push_str(&author);
-
-        if let Some(nick_suffix) = weechat.get_string_option("weechat.look.nick_suffix") {
-            if let Some(color) = weechat.get_string_option("weechat.color.chat_nick_suffix") {
-                prefix.push_str(&colorize_string(
-                    weechat,
-                    &color.value(),
-                    &nick_suffix.value(),
-                ))
-            }
-        }
+        let prefix = nick_prefix(weechat, &author);
 
         use serenity::model::channel::MessageType::*;
         if let Regular = msg.kind {
-            (
-                prefix,
-                formatting::discord_to_weechat(weechat, &msg_content),
-                unknown_users,
-            )
+            let reply = reply_preview(cache, weechat, account, msg, state.referenced, guild)
+                .unwrap_or_default();
+            let content = match struck {
+                Some(struck) => struck,
+                None => formatting::discord_to_weechat(weechat, &msg_content),
+            };
+            let history = edit_history(cache, weechat, account, state.previous_versions, guild);
+            (prefix, reply + &history + &content, unknown_users)
         } else {
             let (prefix, body) = match msg.kind {
                 GroupRecipientAddition | MemberJoin => {
@@ -332,6 +891,175 @@ mod formatting_utils {
         }
     }
 
+    /// Render a dimmed, single line preview of the message being replied to
+    fn reply_preview(
+        cache: &CacheRwLock,
+        weechat: &Weechat,
+        account: &str,
+        msg: &Message,
+        referenced: Option<&Message>,
+        guild: Option<GuildId>,
+    ) -> Option<String> {
+        let reference = msg.message_reference.as_ref()?;
+        // Crossposted messages also carry a reference, but to a message in another channel
+        if reference.message_id.is_none() || reference.channel_id != msg.channel_id {
+            return None;
+        }
+
+        let preview = if let Some(referenced) = referenced {
+            let author = author_display_name(cache, referenced, guild);
+            let mut content = format_emoji(
+                weechat,
+                &crate::utils::clean_emojis(&referenced.content, false),
+            );
+            clean_users(cache, &mut content, false, guild);
+            let content =
+                clean_channels(cache, account, &clean_roles(cache, None, &content, guild));
+
+            let line = content.lines().next().unwrap_or_default();
+            let mut line: String = line.chars().take(REPLY_PREVIEW_LENGTH).collect();
+            if content.chars().count() > line.chars().count() {
+                line.push('…');
+            }
+            if line.is_empty() && !referenced.attachments.is_empty() {
+                line.push_str("[attachment]");
+            }
+
+            format!("@{}: {}", author, line)
+        } else {
+            "original message not loaded".to_owned()
+        };
+
+        Some(format!(
+            "{}↳ {}{}\n",
+            weechat.color("8"),
+            preview,
+            weechat.color("reset")
+        ))
+    }
+
+    /// Render the previous versions of an edited message as dimmed lines
+    fn edit_history(
+        cache: &CacheRwLock,
+        weechat: &Weechat,
+        account: &str,
+        previous_versions: &[Message],
+        guild: Option<GuildId>,
+    ) -> String {
+        let mut history = String::new();
+        for previous in previous_versions {
+            let mut content = format_emoji(
+                weechat,
+                &crate::utils::clean_emojis(&previous.content, false),
+            );
+            clean_users(cache, &mut content, false, guild);
+            let content =
+                clean_channels(cache, account, &clean_roles(cache, None, &content, guild));
+
+            for line in content.lines() {
+                history.push_str(&format!(
+                    "{}✎ {}{}\n",
+                    weechat.color("8"),
+                    line,
+                    weechat.color("reset")
+                ));
+            }
+        }
+        history
+    }
+
+    /// Show unicode emoji as shortcodes, if enabled
+    fn format_emoji(weechat: &Weechat, text: &str) -> String {
+        if crate::upgrade_plugin(weechat)
+            .config
+            .emoji_shortcodes
+            .value()
+        {
+            crate::emoji::replace_with_shortcodes(text)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    /// Surround a colored nick with the nick prefix and suffix set in weechat
+    fn nick_prefix(weechat: &Weechat, author: &str) -> String {
+        let mut prefix = String::new();
+
+        if let Some(nick_prefix) = weechat.get_string_option("weechat.look.nick_prefix") {
+            if let Some(color) = weechat.get_string_option("weechat.color.chat_nick_prefix") {
+                prefix.push_str(&colorize_string(
+                    weechat,
+                    &color.value(),
+                    &nick_prefix.value(),
+                ))
+            }
+        }
+
+        prefix.push_str(author);
+
+        if let Some(nick_suffix) = weechat.get_string_option("weechat.look.nick_suffix") {
+            if let Some(color) = weechat.get_string_option("weechat.color.chat_nick_suffix") {
+                prefix.push_str(&colorize_string(
+                    weechat,
+                    &color.value(),
+                    &nick_suffix.value(),
+                ))
+            }
+        }
+        prefix
+    }
+
+    /// Replace the notify tag of `tags` with the one of a printed line, if it has one
+    pub fn keep_notify_tag(line_tags: &[String], tags: &str) -> String {
+        let notify = match line_tags.iter().find(|tag| tag.starts_with("notify_")) {
+            Some(notify) => notify.as_str(),
+            None => return tags.to_owned(),
+        };
+        tags.split(',')
+            .map(|tag| {
+                if tag.starts_with("notify_") {
+                    notify
+                } else {
+                    tag
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// The tag used to find the printed lines of a message that is being sent
+    pub fn pending_tag(nonce: &str) -> String {
+        format!("discord_pending_{}", nonce)
+    }
+
+    pub fn pending_tags(nonce: &str) -> String {
+        format!(
+            "{},discord_pending,notify_none,no_highlight,self_msg",
+            pending_tag(nonce)
+        )
+    }
+
+    /// Render a message that is being sent, dimmed and marked as sending or failed
+    pub fn render_pending(weechat: &Weechat, pending: &PendingMessage) -> (String, String) {
+        let prefix = nick_prefix(weechat, &format_nick_color(weechat, &pending.author));
+        let mut content = pending
+            .text
+            .lines()
+            .map(|line| colorize_string(weechat, "8", line))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if pending.failed {
+            content.push_str(&colorize_string(
+                weechat,
+                "red",
+                " (failed, /discord retry to resend)",
+            ));
+        } else {
+            content.push_str(&colorize_string(weechat, "8", " (sending)"));
+        }
+        (prefix, content)
+    }
+
     pub fn author_display_name(
         cache: &CacheRwLock,
         msg: &Message,
@@ -346,6 +1074,133 @@ mod formatting_utils {
         display_name.unwrap_or_else(|| msg.author.name.to_owned())
     }
 
+    /// Whether a message should highlight: it mentions the current user or one of their roles,
+    /// `@everyone` or `@here` unless suppressed for the guild, or one of the highlight keywords
+    pub fn is_highlight(
+        cache: &CacheRwLock,
+        weechat: &Weechat,
+        account: &str,
+        msg: &Message,
+        guild: Option<GuildId>,
+    ) -> bool {
+        let current_user = cache.read().user.id;
+        if msg.mentions_user_id(current_user) || mentions_own_role(cache, account, msg, guild) {
+            return true;
+        }
+        let suppressed = guild.map_or(false, |guild| {
+            guild_settings::suppress_everyone(account, guild)
+        });
+        if msg.mention_everyone && !suppressed {
+            return true;
+        }
+
+        let content = msg.content.to_lowercase();
+        crate::upgrade_plugin(weechat)
+            .config
+            .highlight_keywords(account)
+            .iter()
+            .any(|keyword| contains_word(&content, keyword))
+    }
+
+    /// Whether `word` occurs in `text` without being part of a longer word
+    fn contains_word(text: &str, word: &str) -> bool {
+        text.match_indices(word).any(|(start, _)| {
+            let end = start + word.len();
+            let before = text[..start].chars().next_back();
+            let after = text[end..].chars().next();
+            !before.map_or(false, char::is_alphanumeric)
+                && !after.map_or(false, char::is_alphanumeric)
+        })
+    }
+
+    /// Whether a message mentions one of the roles of the current user
+    fn mentions_own_role(
+        cache: &CacheRwLock,
+        account: &str,
+        msg: &Message,
+        guild: Option<GuildId>,
+    ) -> bool {
+        let suppressed = guild.map_or(false, |guild| {
+            guild_settings::suppress_roles(account, guild)
+        });
+        if msg.mention_roles.is_empty() || suppressed {
+            return false;
+        }
+        let current_user = cache.read().user.id;
+        guild
+            .and_then(|guild| cache.read().member(guild, current_user))
+            .map_or(false, |member| {
+                member
+                    .roles
+                    .iter()
+                    .any(|role| msg.mention_roles.contains(role))
+            })
+    }
+
+    /// Replace role mentions with the name of the role, colored like the role if `weechat` is given
+    fn clean_roles(
+        cache: &CacheRwLock,
+        weechat: Option<&Weechat>,
+        s: &str,
+        guild: Option<GuildId>,
+    ) -> String {
+        lazy_static! {
+            static ref ROLE_MENTION: Regex = Regex::new(r"<@&(\d+)>").unwrap();
+        }
+
+        let guild = guild.and_then(|id| id.to_guild_cached(cache));
+        ROLE_MENTION
+            .replace_all(s, |caps: &Captures| {
+                let role = caps[1].parse().ok().and_then(|id| {
+                    let guild = guild.as_ref()?.read();
+                    guild.roles.get(&RoleId(id)).cloned()
+                });
+                let role = match role {
+                    Some(role) => role,
+                    None => return "@deleted-role".to_owned(),
+                };
+                // The role of everyone is named @everyone
+                let name = format!("@{}", role.name.trim_start_matches('@'));
+
+                match weechat {
+                    // Roles without a color have the default color of 0
+                    Some(weechat) if role.colour.0 != 0 => colorize_string(
+                        weechat,
+                        &crate::utils::rgb_to_ansi(role.colour).to_string(),
+                        &name,
+                    ),
+                    _ => name,
+                }
+            })
+            .into_owned()
+    }
+
+    /// Replace channel mentions with the name of the channel
+    fn clean_channels(cache: &CacheRwLock, account: &str, s: &str) -> String {
+        lazy_static! {
+            static ref CHANNEL_MENTION: Regex = Regex::new(r"<#(\d+)>").unwrap();
+        }
+
+        CHANNEL_MENTION
+            .replace_all(s, |caps: &Captures| {
+                let id = match caps[1].parse() {
+                    Ok(id) => ChannelId(id),
+                    Err(_) => return caps[0].to_owned(),
+                };
+                let name = cache
+                    .read()
+                    .channel(id)
+                    .map(|channel| channel.name())
+                    .or_else(|| threads::get(account, id).map(|thread| thread.name));
+
+                match name {
+                    Some(name) => format!("#{}", name),
+                    None => "#deleted-channel".to_owned(),
+                }
+            })
+            .into_owned()
+    }
+
     /// Convert raw mentions into human readable form, returning all ids that were not converted
     /// Extracted from serenity and modified
     fn clean_users(
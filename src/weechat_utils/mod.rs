@@ -1,4 +1,5 @@
 mod buffer_manager;
 pub use buffer_manager::BufferManager;
 mod message_manager;
-pub use message_manager::MessageManager;
+pub use message_manager::{MessageManager, PendingMessage};
+pub mod raw;
@@ -0,0 +1,302 @@
+//! Thin wrappers around weechat api functions not exposed by the weechat crate
+use std::{
+    ffi::{CStr, CString},
+    mem::transmute,
+    os::{
+        raw::{c_int, c_void},
+        unix::io::RawFd,
+    },
+    ptr,
+};
+use weechat::Weechat;
+use weechat_sys::{t_gui_buffer, t_hdata, t_hook, t_weechat_plugin, WEECHAT_RC_OK};
+
+/// Find the raw pointer to one of our buffers
+pub fn buffer_ptr(weechat: &Weechat, name: &str) -> Option<*mut t_gui_buffer> {
+    let plugin = weechat.as_ptr();
+    let plugin_name = CString::new("weecord").ok()?;
+    let name = CString::new(name).ok()?;
+
+    let ptr = unsafe {
+        let buffer_search = (*plugin).buffer_search?;
+        buffer_search(plugin_name.as_ptr(), name.as_ptr())
+    };
+    if ptr.is_null() {
+        None
+    } else {
+        Some(ptr)
+    }
+}
+
+/// Whether one of our buffers is the one currently displayed
+pub fn is_current_buffer(weechat: &Weechat, name: &str) -> bool {
+    let plugin = weechat.as_ptr();
+    let buffer = match buffer_ptr(weechat, name) {
+        Some(buffer) => buffer,
+        None => return false,
+    };
+
+    unsafe {
+        // Searching without a plugin and name finds the current buffer
+        match (*plugin).buffer_search {
+            Some(buffer_search) => buffer_search(ptr::null(), ptr::null()) == buffer,
+            None => false,
+        }
+    }
+}
+
+/// Get an integer property of a buffer, eg: `number`
+pub fn buffer_get_integer(weechat: &Weechat, name: &str, property: &str) -> Option<i32> {
+    let plugin = weechat.as_ptr();
+    let buffer = buffer_ptr(weechat, name)?;
+    let property = CString::new(property).ok()?;
+
+    unsafe {
+        let buffer_get_integer = (*plugin).buffer_get_integer?;
+        Some(buffer_get_integer(buffer, property.as_ptr()))
+    }
+}
+
+/// Get a string property of a buffer, eg: `title`
+pub fn buffer_get_string(weechat: &Weechat, name: &str, property: &str) -> Option<String> {
+    let plugin = weechat.as_ptr();
+    let buffer = buffer_ptr(weechat, name)?;
+    let property = CString::new(property).ok()?;
+
+    unsafe {
+        let buffer_get_string = (*plugin).buffer_get_string?;
+        let value = buffer_get_string(buffer, property.as_ptr());
+        if value.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+        }
+    }
+}
+
+/// Set an arbitrary property of a buffer, eg: `number`
+pub fn buffer_set(weechat: &Weechat, name: &str, property: &str, value: &str) {
+    let plugin = weechat.as_ptr();
+    let buffer = match buffer_ptr(weechat, name) {
+        Some(buffer) => buffer,
+        None => return,
+    };
+    let (property, value) = match (CString::new(property), CString::new(value)) {
+        (Ok(property), Ok(value)) => (property, value),
+        _ => return,
+    };
+
+    unsafe {
+        if let Some(buffer_set) = (*plugin).buffer_set {
+            buffer_set(buffer, property.as_ptr(), value.as_ptr());
+        }
+    }
+}
+
+/// A pointer to the data of a printed line
+#[derive(Debug, Clone, Copy)]
+pub struct LineData(*mut c_void);
+
+unsafe fn hdata(weechat: &Weechat, name: &str) -> Option<*mut t_hdata> {
+    let plugin = weechat.as_ptr();
+    let name = CString::new(name).ok()?;
+    let hdata = ((*plugin).hdata_get?)(plugin, name.as_ptr());
+    if hdata.is_null() {
+        None
+    } else {
+        Some(hdata)
+    }
+}
+
+/// Find the lines of a buffer that have a tag, oldest first
+///
+/// Lines with the same tag are expected to be contiguous, so the search stops at the first
+/// non matching line after a match
+pub fn lines_with_tag(weechat: &Weechat, buffer_name: &str, tag: &str) -> Vec<LineData> {
+    let mut found = Vec::new();
+    let buffer = match buffer_ptr(weechat, buffer_name) {
+        Some(buffer) => buffer,
+        None => return found,
+    };
+
+    unsafe {
+        let plugin = weechat.as_ptr();
+        let (hdata_pointer, hdata_integer, hdata_string, hdata_move) = match (
+            (*plugin).hdata_pointer,
+            (*plugin).hdata_integer,
+            (*plugin).hdata_string,
+            (*plugin).hdata_move,
+        ) {
+            (Some(p), Some(i), Some(s), Some(m)) => (p, i, s, m),
+            _ => return found,
+        };
+        let (buffer_hdata, lines_hdata, line_hdata, line_data_hdata) = match (
+            hdata(weechat, "buffer"),
+            hdata(weechat, "lines"),
+            hdata(weechat, "line"),
+            hdata(weechat, "line_data"),
+        ) {
+            (Some(b), Some(ls), Some(l), Some(d)) => (b, ls, l, d),
+            _ => return found,
+        };
+
+        let own_lines = CString::new("own_lines").unwrap();
+        let last_line = CString::new("last_line").unwrap();
+        let data = CString::new("data").unwrap();
+        let tags_count = CString::new("tags_count").unwrap();
+
+        let lines = hdata_pointer(buffer_hdata, buffer as *mut c_void, own_lines.as_ptr());
+        if lines.is_null() {
+            return found;
+        }
+        let mut line = hdata_pointer(lines_hdata, lines, last_line.as_ptr());
+
+        while !line.is_null() {
+            let line_data = hdata_pointer(line_hdata, line, data.as_ptr());
+            let count = hdata_integer(line_data_hdata, line_data, tags_count.as_ptr());
+
+            let matches = (0..count).any(|i| {
+                let name = CString::new(format!("{}|tags_array", i)).unwrap();
+                let line_tag = hdata_string(line_data_hdata, line_data, name.as_ptr());
+                !line_tag.is_null() && CStr::from_ptr(line_tag).to_bytes() == tag.as_bytes()
+            });
+
+            if matches {
+                found.push(LineData(line_data));
+            } else if !found.is_empty() {
+                break;
+            }
+            line = hdata_move(line_hdata, line, -1);
+        }
+    }
+
+    found.reverse();
+    found
+}
+
+/// The tags of a printed line
+pub fn line_tags(weechat: &Weechat, line: LineData) -> Vec<String> {
+    unsafe {
+        let plugin = weechat.as_ptr();
+        let (hdata_integer, hdata_string) = match ((*plugin).hdata_integer, (*plugin).hdata_string)
+        {
+            (Some(i), Some(s)) => (i, s),
+            _ => return Vec::new(),
+        };
+        let line_data_hdata = match hdata(weechat, "line_data") {
+            Some(hdata) => hdata,
+            None => return Vec::new(),
+        };
+
+        let tags_count = CString::new("tags_count").unwrap();
+        let count = hdata_integer(line_data_hdata, line.0, tags_count.as_ptr());
+        (0..count)
+            .filter_map(|i| {
+                let name = CString::new(format!("{}|tags_array", i)).unwrap();
+                let tag = hdata_string(line_data_hdata, line.0, name.as_ptr());
+                if tag.is_null() {
+                    None
+                } else {
+                    Some(CStr::from_ptr(tag).to_string_lossy().into_owned())
+                }
+            })
+            .collect()
+    }
+}
+
+/// Replace the message and tags of a printed line
+pub fn update_line(weechat: &Weechat, line: LineData, message: &str, tags: &str) -> bool {
+    let (message, tags) = match (CString::new(message), CString::new(tags)) {
+        (Ok(message), Ok(tags)) => (message, tags),
+        _ => return false,
+    };
+
+    unsafe {
+        let plugin = weechat.as_ptr();
+        let (hashtable_new, hashtable_set, hashtable_free, hdata_update) = match (
+            (*plugin).hashtable_new,
+            (*plugin).hashtable_set,
+            (*plugin).hashtable_free,
+            (*plugin).hdata_update,
+        ) {
+            (Some(n), Some(s), Some(f), Some(u)) => (n, s, f, u),
+            _ => return false,
+        };
+        let line_data_hdata = match hdata(weechat, "line_data") {
+            Some(hdata) => hdata,
+            None => return false,
+        };
+
+        let string_type = CString::new("string").unwrap();
+        let message_key = CString::new("message").unwrap();
+        let tags_key = CString::new("tags_array").unwrap();
+
+        let hashtable = hashtable_new(8, string_type.as_ptr(), string_type.as_ptr(), None, None);
+        if hashtable.is_null() {
+            return false;
+        }
+        hashtable_set(
+            hashtable,
+            message_key.as_ptr() as *const c_void,
+            message.as_ptr() as *const c_void,
+        );
+        hashtable_set(
+            hashtable,
+            tags_key.as_ptr() as *const c_void,
+            tags.as_ptr() as *const c_void,
+        );
+        let updated = hdata_update(line_data_hdata, line.0, hashtable);
+        hashtable_free(hashtable);
+
+        updated > 0
+    }
+}
+
+/// A hook created with [`hook_fd_read`], unhooked when dropped
+pub struct FdHook {
+    plugin: *mut t_weechat_plugin,
+    hook: *mut t_hook,
+}
+
+impl Drop for FdHook {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(unhook) = (*self.plugin).unhook {
+                unhook(self.hook);
+            }
+        }
+    }
+}
+
+/// Run a callback on the main thread whenever a file descriptor becomes readable
+pub fn hook_fd_read(weechat: &Weechat, fd: RawFd, callback: fn()) -> Option<FdHook> {
+    unsafe extern "C" fn trampoline(
+        pointer: *const c_void,
+        _data: *mut c_void,
+        _fd: c_int,
+    ) -> c_int {
+        let callback = transmute::<*const c_void, fn()>(pointer);
+        callback();
+        WEECHAT_RC_OK as c_int
+    }
+
+    let plugin = weechat.as_ptr();
+    let hook = unsafe {
+        let hook_fd = (*plugin).hook_fd?;
+        hook_fd(
+            plugin,
+            fd,
+            1,
+            0,
+            0,
+            Some(trampoline),
+            callback as *const c_void,
+            ptr::null_mut(),
+        )
+    };
+    if hook.is_null() {
+        None
+    } else {
+        Some(FdHook { plugin, hook })
+    }
+}